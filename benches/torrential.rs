@@ -1,46 +1,503 @@
 use std::{
     cmp,
+    collections::HashMap,
     fs::File,
-    io::{BufWriter, Write},
+    io::{BufWriter, Error, Write},
+    net::TcpListener as StdTcpListener,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use criterion::{Criterion, criterion_group, criterion_main};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use dashmap::DashSet;
+use futures_util::{SinkExt, StreamExt, future::join_all};
+use protobuf::{Enum, EnumOrUnknown, Message};
 use rand::{Rng, rng};
-use tempfile::tempfile;
-use tokio::runtime::Runtime;
+use sha2::{Digest, Sha256};
+use tempfile::{TempDir, tempdir, tempfile};
+use tokio::{
+    io::AsyncSeekExt,
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    spawn,
+    sync::mpsc,
+    time::sleep,
+};
+use tokio_util::{codec::Framed, io::ReaderStream, sync::CancellationToken};
+use torrential::{
+    app::setup_app,
+    config::{Args, Config, LinkConfig, SharedConfig},
+    downloads::{
+        cache::ContextCache,
+        encrypt::EncryptingStream,
+        range::ByteRange,
+        sendfile,
+        serve::{Aes128Ctr64LE, file_stream},
+    },
+    proto::{
+        core::{DropBound, DropBoundType, Heartbeat, Hello, TorrentialBound, TorrentialBoundType},
+        version::{
+            VersionQuery, VersionResponse,
+            version_response::{
+                LibrarySource, Manifest,
+                library_source::LibraryBackend,
+                manifest::{ChunkData, chunk_data::FileEntry},
+            },
+        },
+    },
+    server::{
+        codec::{Frame, FrameCodec},
+        create_drop_server,
+    },
+    state::AppState,
+};
 
-async fn torrential() {}
-
-fn generate_file() -> File {
-    let total_bytes = 312 * 1024 * 1024;
-    let tempfile = tempfile().unwrap();
-    let mut writer = BufWriter::new(tempfile);
+const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+const BUFFER_SIZE: usize = 256 * 1024;
+const GAME_ID: &str = "game";
 
+fn write_random(writer: &mut impl Write, size: usize) {
     let mut rng = rng();
     let mut buffer = [0; 1024];
-    let mut remaining_size = total_bytes;
+    let mut remaining_size = size;
 
     while remaining_size > 0 {
         let to_write = cmp::min(remaining_size, buffer.len());
         let buffer = &mut buffer[..to_write];
         rng.fill(buffer);
-        writer.write(buffer).unwrap();
+        writer.write_all(buffer).unwrap();
 
         remaining_size -= to_write;
     }
+}
+
+fn generate_file() -> File {
+    let mut writer = BufWriter::new(tempfile().unwrap());
+    write_random(&mut writer, CHUNK_SIZE);
     writer.into_inner().unwrap()
 }
-// The benchmark function setup
-fn benchmark(c: &mut Criterion) {
+
+/**
+A chunk read from the game's files, encrypted as it's sent
+*/
+async fn from_source(file: &File, buffer_size: usize) -> usize {
+    let mut file = tokio::fs::File::from_std(file.try_clone().unwrap());
+    file.rewind().await.unwrap();
+    let cipher = Aes128Ctr64LE::new(&[3; 16].into(), &[9; 16].into());
+    let stream = EncryptingStream::new([file], cipher, buffer_size);
+    stream
+        .map(|data| data.unwrap().len())
+        .fold(0, |total, len| async move { total + len })
+        .await
+}
+
+/**
+A chunk read from the game's files and encrypted the way it was before
+`EncryptingStream`, batching 16 of `ReaderStream`'s reads into a new
+buffer at a time
+*/
+async fn from_source_batched(file: &File) -> usize {
+    let mut file = tokio::fs::File::from_std(file.try_clone().unwrap());
+    file.rewind().await.unwrap();
+    let mut cipher = Aes128Ctr64LE::new(&[3; 16].into(), &[9; 16].into());
+    let stream = ReaderStream::new(file).chunks(16).map(move |raw| {
+        let data: Result<Vec<Bytes>, Error> = raw.into_iter().collect();
+        let mut data = data?.concat();
+        cipher.apply_keystream(&mut data);
+        Ok::<_, Error>(Bytes::from(data))
+    });
+    stream
+        .map(|data| data.unwrap().len())
+        .fold(0, |total, len| async move { total + len })
+        .await
+}
+
+/**
+A chunk that's already encrypted in the chunk cache, sent as is
+*/
+async fn from_cache(file: &File) -> usize {
+    let file = tokio::fs::File::from_std(file.try_clone().unwrap());
+    let stream = file_stream(file, ByteRange::full(CHUNK_SIZE))
+        .await
+        .unwrap();
+    stream
+        .map(|data| data.unwrap().len())
+        .fold(0, |total, len| async move { total + len })
+        .await
+}
+
+fn streams(c: &mut Criterion) {
     let rt = Runtime::new().unwrap();
 
     let file = generate_file();
 
-    c.bench_function("torrential download", |b| {
-        b.to_async(&rt).iter(|| torrential())
+    let mut group = c.benchmark_group("single file chunk");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    group.sample_size(20);
+    group.bench_function("from source", |b| {
+        b.to_async(&rt).iter(|| from_source(&file, BUFFER_SIZE));
     });
+    group.bench_function("from cache", |b| {
+        b.to_async(&rt).iter(|| from_cache(&file));
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("encrypting");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    group.sample_size(20);
+    group.bench_function("batched", |b| {
+        b.to_async(&rt).iter(|| from_source_batched(&file));
+    });
+    group.bench_function("in place", |b| {
+        b.to_async(&rt).iter(|| from_source(&file, BUFFER_SIZE));
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("read buffer size");
+    group.throughput(Throughput::Bytes(CHUNK_SIZE as u64));
+    group.sample_size(20);
+    for buffer_size in [16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(buffer_size),
+            &buffer_size,
+            |b, &buffer_size| {
+                b.to_async(&rt).iter(|| from_source(&file, buffer_size));
+            },
+        );
+    }
+    group.finish();
+}
+
+/**
+A version written out under the library, and what Drop would answer a
+`VERSION_QUERY` for it with
+*/
+struct Version {
+    id: &'static str,
+    chunk_ids: Vec<String>,
+    chunk_size: usize,
+    response: VersionResponse,
+}
+
+/**
+Writes `files` files of `file_size` random bytes, then splits them into
+chunks of `chunk_size` bytes in order, the way manifests are generated
+*/
+fn write_version(
+    library: &Path,
+    id: &'static str,
+    files: usize,
+    file_size: usize,
+    chunk_size: usize,
+) -> Version {
+    let dir = library.join(GAME_ID).join(id);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut manifest = Manifest {
+        version: id.to_owned(),
+        key: rng().random::<[u8; 16]>().to_vec(),
+        ..Default::default()
+    };
+
+    let mut chunk = ChunkData::new();
+    let mut chunk_length = 0;
+    let mut hasher = Sha256::new();
+    let mut finish_chunk = |chunk: &mut ChunkData, hasher: &mut Sha256| {
+        let mut chunk = std::mem::take(chunk);
+        chunk.iv = rng().random::<[u8; 16]>().to_vec();
+        chunk.checksum = format!("{:x}", std::mem::take(hasher).finalize());
+        let chunk_id = format!("chunk-{}", manifest.chunks.len());
+        manifest.chunks.insert(chunk_id, chunk);
+    };
+
+    for index in 0..files {
+        let filename = format!("{index:05}.bin");
+        let mut data = Vec::with_capacity(file_size);
+        write_random(&mut data, file_size);
+        std::fs::write(dir.join(&filename), &data).unwrap();
+
+        let mut start = 0;
+        while start < file_size {
+            let length = cmp::min(file_size - start, chunk_size - chunk_length);
+            let mut entry = FileEntry::new();
+            entry.filename.clone_from(&filename);
+            entry.start = start as u64;
+            entry.length = length as u64;
+            chunk.files.push(entry);
+            hasher.update(&data[start..start + length]);
+
+            start += length;
+            chunk_length += length;
+            if chunk_length == chunk_size {
+                finish_chunk(&mut chunk, &mut hasher);
+                chunk_length = 0;
+            }
+        }
+    }
+    if chunk_length > 0 {
+        finish_chunk(&mut chunk, &mut hasher);
+    }
+    manifest.size = (files * file_size) as u64;
+
+    let mut chunk_ids = manifest.chunks.keys().cloned().collect::<Vec<_>>();
+    chunk_ids.sort();
+
+    let mut source = LibrarySource::new();
+    source.options = serde_json::json!({ "baseDir": library }).to_string();
+    source.backend = EnumOrUnknown::new(LibraryBackend::FILESYSTEM);
+
+    let response = VersionResponse {
+        manifest: Some(manifest).into(),
+        source: Some(source).into(),
+        library_path: GAME_ID.to_owned(),
+        version_path: id.to_owned(),
+        ..Default::default()
+    };
+
+    Version {
+        id,
+        chunk_ids,
+        chunk_size,
+        response,
+    }
+}
+
+/**
+Stands in for Drop on the link, answering hellos, pings and version
+queries, and nothing else
+*/
+async fn stub_drop(address: String, versions: HashMap<String, VersionResponse>) {
+    let stream = loop {
+        match TcpStream::connect(&address).await {
+            Ok(stream) => break stream,
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    };
+    let mut link = Framed::new(stream, FrameCodec::new(usize::MAX));
+
+    while let Some(Ok(Frame::Whole(frame))) = link.next().await {
+        let message = DropBound::parse_from_bytes(&frame).unwrap();
+        let (message_type, data) = match message.type_.enum_value() {
+            Ok(DropBoundType::HELLO) => {
+                let mut hello = Hello::new();
+                hello.protocol_version = 1;
+                hello.minimum_protocol_version = 1;
+                hello.supported_types = vec![
+                    DropBoundType::PING.value(),
+                    DropBoundType::VERSION_QUERY.value(),
+                ];
+                (TorrentialBoundType::HELLO, hello.write_to_bytes())
+            }
+            Ok(DropBoundType::PING) => {
+                (TorrentialBoundType::PONG, Heartbeat::new().write_to_bytes())
+            }
+            Ok(DropBoundType::VERSION_QUERY) => {
+                let query = VersionQuery::parse_from_bytes(&message.data).unwrap();
+                (
+                    TorrentialBoundType::VERSION_RESPONSE,
+                    versions[&query.version_id].write_to_bytes(),
+                )
+            }
+            _ => continue,
+        };
+
+        let mut reply = TorrentialBound::new();
+        reply.message_id = message.message_id;
+        reply.type_ = EnumOrUnknown::new(message_type);
+        reply.data = data.unwrap();
+        link.send(&reply.write_to_bytes().unwrap()[..])
+            .await
+            .unwrap();
+    }
+}
+
+/**
+Starts torrential's Drop link on a free port, with the stub Drop connected,
+caching chunks in `chunk_cache_dir` if there is one
+*/
+async fn start_state(
+    working_directory: &Path,
+    versions: &[Version],
+    chunk_cache_dir: Option<PathBuf>,
+) -> Arc<AppState> {
+    let link_port = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut config = Config {
+        manifest_cache_dir: working_directory.join("manifest-cache"),
+        link: LinkConfig {
+            address: format!("tcp://127.0.0.1:{link_port}"),
+            // The stub doesn't do the handshake
+            allow_unauthenticated: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    config.chunk_cache.dir = chunk_cache_dir;
+    let config = Arc::new(SharedConfig::new(Args::default(), config));
+
+    let context_cache = Arc::new(ContextCache::new(config.clone()));
+    let (prewarm_requests, _) = mpsc::unbounded_channel();
+    let server = create_drop_server(config.clone(), context_cache.clone(), prewarm_requests)
+        .await
+        .unwrap();
+
+    let responses = versions
+        .iter()
+        .map(|version| (version.id.to_owned(), version.response.clone()))
+        .collect();
+    spawn(stub_drop(format!("127.0.0.1:{link_port}"), responses));
+    server.connected().await;
+
+    Arc::new(AppState {
+        config,
+        context_cache,
+        server,
+        library_dirs: DashSet::new(),
+    })
+}
+
+/**
+Serves `state` on a free port, through hyper alone or with whole cached
+chunks sent by `sendfile`, returning where to send requests
+*/
+async fn start_server(state: Arc<AppState>, with_sendfile: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = setup_app(state.clone());
+    if with_sendfile {
+        spawn(sendfile::serve(
+            listener,
+            app,
+            state,
+            CancellationToken::new(),
+        ));
+    } else {
+        spawn(async move { axum::serve(listener, app).await });
+    }
+
+    format!("http://{address}")
+}
+
+fn chunk_url(base: &str, version: &Version, index: usize) -> String {
+    let chunk_id = &version.chunk_ids[index % version.chunk_ids.len()];
+    format!(
+        "{base}/api/v1/depot/content/{GAME_ID}/{}/{chunk_id}",
+        version.id
+    )
+}
+
+/**
+Downloads a whole chunk, returning how many bytes it was
+*/
+async fn download(client: &reqwest::Client, url: &str) -> usize {
+    let mut response = client.get(url).send().await.unwrap();
+    assert!(response.status().is_success());
+    let mut length = 0;
+    while let Some(data) = response.chunk().await.unwrap() {
+        length += data.len();
+    }
+    length
+}
+
+/**
+Waits for the first bytes of a chunk, then hangs up
+*/
+async fn first_byte(client: &reqwest::Client, url: &str) {
+    let mut response = client.get(url).send().await.unwrap();
+    assert!(response.status().is_success());
+    response.chunk().await.unwrap();
+}
+
+fn serving(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let working_directory: TempDir = tempdir().unwrap();
+    let library = working_directory.path().join("library");
+
+    let versions = [
+        write_version(&library, "small-files", 2048, 8 * 1024, 4 * 1024 * 1024),
+        write_version(&library, "large-file", 1, 2 * CHUNK_SIZE, CHUNK_SIZE),
+    ];
+    let base = rt.block_on(async {
+        let state = start_state(working_directory.path(), &versions, None).await;
+        start_server(state, false).await
+    });
+    let client = reqwest::Client::new();
+
+    // Creates the download contexts, so they aren't part of the first sample
+    rt.block_on(async {
+        for version in &versions {
+            download(&client, &chunk_url(&base, version, 0)).await;
+        }
+    });
+
+    let mut group = c.benchmark_group("chunk throughput");
+    group.sample_size(10);
+    for version in &versions {
+        let url = chunk_url(&base, version, 0);
+        group.throughput(Throughput::Bytes(version.chunk_size as u64));
+        group.bench_function(version.id, |b| {
+            b.to_async(&rt).iter(|| download(&client, &url));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("first byte");
+    for version in &versions {
+        let url = chunk_url(&base, version, 0);
+        group.bench_function(version.id, |b| {
+            b.to_async(&rt).iter(|| first_byte(&client, &url));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("concurrent clients");
+    group.sample_size(10);
+    for version in &versions {
+        for clients in [1, 4, 16] {
+            let urls = (0..clients)
+                .map(|index| chunk_url(&base, version, index))
+                .collect::<Vec<_>>();
+            group.throughput(Throughput::Bytes((version.chunk_size * clients) as u64));
+            group.bench_with_input(BenchmarkId::new(version.id, clients), &urls, |b, urls| {
+                b.to_async(&rt)
+                    .iter(|| join_all(urls.iter().map(|url| download(&client, url))));
+            });
+        }
+    }
+    group.finish();
+
+    let cached = working_directory.path().join("cached");
+    let (through_hyper, with_sendfile) = rt.block_on(async {
+        let state = start_state(&cached, &versions, Some(cached.join("chunks"))).await;
+        let through_hyper = start_server(state.clone(), false).await;
+        let with_sendfile = start_server(state.clone(), true).await;
+
+        // The first download fills the cache in the background
+        download(&client, &chunk_url(&through_hyper, &versions[1], 0)).await;
+        while state.context_cache.chunk_cache().stats().entries == 0 {
+            sleep(Duration::from_millis(50)).await;
+        }
+        (through_hyper, with_sendfile)
+    });
+
+    let mut group = c.benchmark_group("cached chunk");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(versions[1].chunk_size as u64));
+    for (name, base) in [("hyper", &through_hyper), ("sendfile", &with_sendfile)] {
+        let url = chunk_url(base, &versions[1], 0);
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| download(&client, &url));
+        });
+    }
+    group.finish();
 }
 
-// Grouping your benchmarks
-criterion_group!(benches, benchmark);
+criterion_group!(benches, streams, serving);
 criterion_main!(benches);
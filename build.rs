@@ -15,7 +15,9 @@ fn main() {
             }
     });
 
+    // Parses the protos in Rust, so building doesn't need protoc installed
     Codegen::new()
+        .pure()
         .inputs(files)
         .include("proto")
         .out_dir(OUT_DIR)
@@ -0,0 +1,23 @@
+#![no_main]
+
+//! Throws arbitrary bytes from the Drop link at the frame codec, and at
+//! the message decoding after it. Run with `cargo +nightly fuzz run frame_codec`
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use protobuf::Message;
+use tokio_util::codec::Decoder;
+use torrential::{proto::core::TorrentialBound, server::codec::{Frame, FrameCodec}};
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let mut codec = FrameCodec::new(MAX_FRAME_SIZE);
+    let mut buffer = BytesMut::from(data);
+    while let Ok(Some(Frame::Whole(frame))) = codec.decode(&mut buffer) {
+        assert!(frame.len() <= MAX_FRAME_SIZE);
+        if let Ok(message) = TorrentialBound::parse_from_bytes(&frame) {
+            let _ = message.type_.enum_value();
+        }
+    }
+});
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use axum::{
+    Router,
+    routing::{get, post},
+};
+
+use crate::{
+    downloads::{handlers, health, serve},
+    state::AppState,
+};
+
+/**
+The depot API and healthchecks, served to everyone
+*/
+pub fn setup_app(shared_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route(
+            "/api/v1/depot/content/{game_id}/{version_name}/{chunk_id}",
+            get(serve::serve_file),
+        )
+        .route("/api/v1/depot/manifest.json", get(handlers::manifest))
+        .route("/api/v1/depot/speedtest", get(handlers::speedtest))
+        .route("/healthcheck", get(health::healthcheck))
+        .route("/healthcheck/live", get(health::liveness))
+        .route("/healthcheck/ready", get(health::readiness))
+        .with_state(shared_state)
+}
+
+/**
+Routes for Drop and monitoring only, served on `admin_bind_address`
+*/
+pub fn setup_admin_app(shared_state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/invalidate", post(handlers::invalidate))
+        .route("/reload", post(handlers::reload))
+        .route("/prewarm", post(handlers::prewarm))
+        .route("/scrub", post(handlers::scrub))
+        .route("/metrics", get(handlers::metrics))
+        .with_state(shared_state)
+}
@@ -0,0 +1,871 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context as _, anyhow};
+use clap::Parser;
+use log::{LevelFilter, info, warn};
+use protobuf::Enum;
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use tokio::sync::watch;
+
+use crate::{
+    downloads::compression::Compression, proto::core::DropBoundType, server::transport::LinkAddress,
+};
+
+/**
+Depot server for Drop.
+
+Every flag can also be set with the environment variable listed next to
+it, and overrides the config file.
+*/
+#[derive(Parser, Debug, Default, Clone)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML configuration file
+    #[arg(short, long, env = "TORRENTIAL_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to serve the depot API on
+    #[arg(long, env = "BIND_ADDRESS")]
+    pub bind_address: Option<SocketAddr>,
+    /// Address to serve the admin endpoints on, for Drop and monitoring
+    #[arg(long, env = "ADMIN_BIND_ADDRESS")]
+    pub admin_bind_address: Option<SocketAddr>,
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    #[arg(long, env = "WORKING_DIRECTORY")]
+    pub working_directory: Option<PathBuf>,
+    /// Seconds an unused download context is kept for
+    #[arg(long, env = "CONTEXT_TTL")]
+    pub context_ttl: Option<u64>,
+    /// Most download contexts kept in memory
+    #[arg(long, env = "CONTEXT_CACHE_ENTRIES")]
+    pub context_cache_entries: Option<usize>,
+    /// Most manifest chunks kept in memory across all download contexts
+    #[arg(long, env = "CONTEXT_CACHE_CHUNKS")]
+    pub context_cache_chunks: Option<usize>,
+    /// Download contexts created at once, across all games
+    #[arg(long, env = "CONTEXT_CREATIONS")]
+    pub context_creations: Option<usize>,
+    /// Where manifests are kept between restarts
+    #[arg(long, env = "MANIFEST_CACHE_DIR")]
+    pub manifest_cache_dir: Option<PathBuf>,
+    /// Most bytes of manifests kept on disk
+    #[arg(long, env = "MANIFEST_CACHE_MAX_BYTES")]
+    pub manifest_cache_max_bytes: Option<u64>,
+    /// Check chunks against their checksums as they're served
+    #[arg(long, env = "VERIFY_CHUNKS")]
+    pub verify_chunks: Option<bool>,
+    /// Chunk compressions offered to clients, in order of preference
+    #[arg(long, env = "COMPRESSION", value_delimiter = ',')]
+    pub compression: Option<Vec<Compression>>,
+    /// Where encrypted chunks are kept, if anywhere
+    #[arg(long, env = "CHUNK_CACHE_DIR")]
+    pub chunk_cache_dir: Option<PathBuf>,
+    /// Most bytes of encrypted chunks kept on disk
+    #[arg(long, env = "CHUNK_CACHE_MAX_BYTES")]
+    pub chunk_cache_max_bytes: Option<u64>,
+    /// Bytes read from disk at a time for each response
+    #[arg(long, env = "READ_BUFFER_SIZE")]
+    pub read_buffer_size: Option<usize>,
+    /// Bytes sent by the speedtest endpoint
+    #[arg(long, env = "SPEEDTEST_SIZE")]
+    pub speedtest_size: Option<usize>,
+    /// Files read in parallel while generating manifests
+    #[arg(long, env = "READER_THREADS")]
+    pub reader_threads: Option<usize>,
+    /// Seconds downloads in progress get to finish when shutting down
+    #[arg(long, env = "SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: Option<u64>,
+    /// Where to listen for Drop, `tcp://host:port` or `unix:///path`
+    #[arg(long, env = "DROP_LINK")]
+    pub link: Option<String>,
+    /// Octal file mode for the Drop link's Unix socket
+    #[arg(long, env = "DROP_LINK_SOCKET_MODE", value_parser = parse_octal)]
+    pub link_socket_mode: Option<u32>,
+    #[arg(long, env = "DROP_LINK_SECRET", hide_env_values = true)]
+    pub link_secret: Option<String>,
+    #[arg(long, env = "DROP_LINK_SECRET_FILE")]
+    pub link_secret_file: Option<PathBuf>,
+    /// Accept Drop connections without a link secret
+    #[arg(long, env = "DROP_LINK_ALLOW_UNAUTHENTICATED")]
+    pub link_allow_unauthenticated: Option<bool>,
+    #[arg(long, env = "DROP_LINK_MAX_FRAME_SIZE")]
+    pub link_max_frame_size: Option<usize>,
+    /// Seconds Drop gets to authenticate after connecting
+    #[arg(long, env = "DROP_LINK_HANDSHAKE_TIMEOUT")]
+    pub link_handshake_timeout: Option<u64>,
+    /// Seconds between heartbeats sent to Drop
+    #[arg(long, env = "DROP_LINK_HEARTBEAT_INTERVAL")]
+    pub link_heartbeat_interval: Option<u64>,
+    #[arg(long, env = "DROP_LINK_MISSED_HEARTBEATS")]
+    pub link_missed_heartbeats: Option<u32>,
+    /// Default seconds to wait for Drop to answer a query
+    #[arg(long, env = "RPC_TIMEOUT")]
+    pub rpc_timeout: Option<u64>,
+}
+
+fn parse_octal(value: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    /// Endpoints that change or expose torrential's state are only served
+    /// here, so this shouldn't be reachable by clients
+    pub admin_bind_address: SocketAddr,
+    #[serde(deserialize_with = "deserialize_level")]
+    pub log_level: LevelFilter,
+    pub working_directory: Option<PathBuf>,
+    /// Seconds an unused download context is kept for
+    pub context_ttl: u64,
+    pub context_cache_entries: usize,
+    /// Budget for the context cache, in manifest chunks
+    pub context_cache_chunks: usize,
+    pub context_creations: usize,
+    /// Manifests from Drop are kept here, so they can be served after a
+    /// restart without asking Drop first
+    pub manifest_cache_dir: PathBuf,
+    pub manifest_cache_max_bytes: u64,
+    /// Hash whole chunks as they're served, and cut the response off if
+    /// they don't match their checksum. Range requests aren't checked
+    pub verify_chunks: bool,
+    /// Bytes read and encrypted at a time for each response
+    pub read_buffer_size: usize,
+    pub speedtest_size: usize,
+    pub reader_threads: usize,
+    /// Seconds downloads in progress get to finish when shutting down
+    pub shutdown_timeout: u64,
+    pub link: LinkConfig,
+    pub rpc: RpcConfig,
+    pub compression: CompressionConfig,
+    pub chunk_cache: ChunkCacheConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 5000)),
+            admin_bind_address: SocketAddr::from(([127, 0, 0, 1], 5001)),
+            log_level: LevelFilter::Info,
+            working_directory: None,
+            context_ttl: 10 * 60,
+            context_cache_entries: 256,
+            context_cache_chunks: 1_000_000,
+            context_creations: 8,
+            manifest_cache_dir: PathBuf::from("manifest-cache"),
+            manifest_cache_max_bytes: 1024 * 1024 * 1024,
+            verify_chunks: false,
+            read_buffer_size: 256 * 1024,
+            speedtest_size: 1024 * 1024 * 50,
+            reader_threads: (num_cpus::get() / 2).max(1),
+            shutdown_timeout: 30,
+            link: LinkConfig::default(),
+            rpc: RpcConfig::default(),
+            compression: CompressionConfig::default(),
+            chunk_cache: ChunkCacheConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConfig {
+    pub address: String,
+    pub socket_mode: u32,
+    pub secret: Option<Secret>,
+    pub secret_file: Option<PathBuf>,
+    /// Without a secret, anyone who can reach the link can send Drop's
+    /// messages, so running without one has to be asked for
+    pub allow_unauthenticated: bool,
+    pub max_frame_size: usize,
+    /// Seconds Drop has to authenticate and say hello
+    pub handshake_timeout: u64,
+    pub heartbeat_interval: u64,
+    pub missed_heartbeats: u32,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            address: "tcp://127.0.0.1:33148".to_owned(),
+            socket_mode: 0o600,
+            secret: None,
+            secret_file: None,
+            allow_unauthenticated: false,
+            max_frame_size: 64 * 1024 * 1024,
+            handshake_timeout: 10,
+            heartbeat_interval: 10,
+            missed_heartbeats: 3,
+        }
+    }
+}
+
+impl LinkConfig {
+    #[must_use]
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout)
+    }
+
+    #[must_use]
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+}
+
+/**
+Deadlines for queries sent to Drop, in seconds. `timeouts` overrides
+`timeout` for single message types, e.g. `VERSION_QUERY = 10`
+*/
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub timeout: u64,
+    pub timeouts: HashMap<String, u64>,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 30,
+            timeouts: HashMap::new(),
+        }
+    }
+}
+
+impl RpcConfig {
+    #[must_use]
+    pub fn timeout_for(&self, message_type: DropBoundType) -> Duration {
+        let timeout = self
+            .timeouts
+            .get(&format!("{message_type:?}"))
+            .copied()
+            .unwrap_or(self.timeout);
+        Duration::from_secs(timeout)
+    }
+}
+
+/**
+Chunk compression, for clients that ask for it. Off unless `algorithms`
+lists at least one. Compressed chunks are only served from the chunk
+cache, so it needs that too
+*/
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// In order of preference
+    pub algorithms: Vec<Compression>,
+    /// Defaults to each algorithm's own default
+    pub level: Option<i32>,
+    /// Games (`game`) or versions (`game/version`) never compressed, e.g.
+    /// because their files already are
+    pub exclude: Vec<String>,
+}
+
+impl CompressionConfig {
+    /**
+    The algorithms offered for a version, in order of preference
+    */
+    #[must_use]
+    pub fn offered_for(&self, game_id: &str, version_id: &str) -> &[Compression] {
+        let excluded = self
+            .exclude
+            .iter()
+            .any(|excluded| match excluded.split_once('/') {
+                Some((game, version)) => game == game_id && version == version_id,
+                None => excluded == game_id,
+            });
+        if excluded { &[] } else { &self.algorithms }
+    }
+}
+
+/**
+Encrypted chunks kept on disk, so popular ones are sent straight from a
+single file. Off unless `dir` is set
+*/
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkCacheConfig {
+    pub dir: Option<PathBuf>,
+    /// When over, the least recently used chunks are removed first
+    pub max_bytes: u64,
+}
+
+impl Default for ChunkCacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_bytes: 10 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/**
+Keeps the link secret out of logs
+*/
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LevelFilter, D::Error> {
+    let level = String::deserialize(deserializer)?;
+    level.parse().map_err(D::Error::custom)
+}
+
+impl Config {
+    #[must_use]
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+
+    /**
+    Builds the configuration from the file given in `args` (if any), then
+    the environment and command line flags, and checks it makes sense
+    */
+    pub fn load(args: &Args) -> Result<Self, anyhow::Error> {
+        Self::load_with_env(args, |name| std::env::var(name).ok())
+    }
+
+    /**
+    `load`, reading the variables that aren't flags from `env`, so tests
+    don't see the real environment
+    */
+    fn load_with_env(
+        args: &Args,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_args(args);
+        config.apply_rpc_env(env)?;
+        config.resolve_secret()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        fn set<T: Clone>(field: &mut T, value: Option<&T>) {
+            if let Some(value) = value {
+                field.clone_from(value);
+            }
+        }
+
+        set(&mut self.bind_address, args.bind_address.as_ref());
+        set(
+            &mut self.admin_bind_address,
+            args.admin_bind_address.as_ref(),
+        );
+        set(&mut self.log_level, args.log_level.as_ref());
+        if args.working_directory.is_some() {
+            self.working_directory.clone_from(&args.working_directory);
+        }
+        set(&mut self.context_ttl, args.context_ttl.as_ref());
+        set(
+            &mut self.context_cache_entries,
+            args.context_cache_entries.as_ref(),
+        );
+        set(
+            &mut self.context_cache_chunks,
+            args.context_cache_chunks.as_ref(),
+        );
+        set(&mut self.context_creations, args.context_creations.as_ref());
+        set(
+            &mut self.manifest_cache_dir,
+            args.manifest_cache_dir.as_ref(),
+        );
+        set(
+            &mut self.manifest_cache_max_bytes,
+            args.manifest_cache_max_bytes.as_ref(),
+        );
+        set(&mut self.verify_chunks, args.verify_chunks.as_ref());
+        set(&mut self.read_buffer_size, args.read_buffer_size.as_ref());
+        set(&mut self.speedtest_size, args.speedtest_size.as_ref());
+        set(&mut self.reader_threads, args.reader_threads.as_ref());
+        set(&mut self.shutdown_timeout, args.shutdown_timeout.as_ref());
+
+        let link = &mut self.link;
+        set(&mut link.address, args.link.as_ref());
+        set(&mut link.socket_mode, args.link_socket_mode.as_ref());
+        if let Some(secret) = &args.link_secret {
+            link.secret = Some(Secret(secret.clone()));
+        }
+        if args.link_secret_file.is_some() {
+            link.secret_file.clone_from(&args.link_secret_file);
+        }
+        set(
+            &mut link.allow_unauthenticated,
+            args.link_allow_unauthenticated.as_ref(),
+        );
+        set(&mut link.max_frame_size, args.link_max_frame_size.as_ref());
+        set(
+            &mut link.handshake_timeout,
+            args.link_handshake_timeout.as_ref(),
+        );
+        set(
+            &mut link.heartbeat_interval,
+            args.link_heartbeat_interval.as_ref(),
+        );
+        set(
+            &mut link.missed_heartbeats,
+            args.link_missed_heartbeats.as_ref(),
+        );
+
+        set(&mut self.rpc.timeout, args.rpc_timeout.as_ref());
+
+        if args.chunk_cache_dir.is_some() {
+            self.chunk_cache.dir.clone_from(&args.chunk_cache_dir);
+        }
+        set(
+            &mut self.chunk_cache.max_bytes,
+            args.chunk_cache_max_bytes.as_ref(),
+        );
+
+        set(&mut self.compression.algorithms, args.compression.as_ref());
+    }
+
+    /**
+    `RPC_TIMEOUT_<TYPE>` (e.g. `RPC_TIMEOUT_VERSION_QUERY`) overrides the
+    deadline for a single message type
+    */
+    fn apply_rpc_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), anyhow::Error> {
+        for message_type in DropBoundType::VALUES {
+            let name = format!("{message_type:?}");
+            let variable = format!("RPC_TIMEOUT_{name}");
+            if let Some(value) = env(&variable) {
+                let timeout = value.parse().with_context(|| {
+                    format!("{variable} must be a number of seconds, not {value:?}")
+                })?;
+                self.rpc.timeouts.insert(name, timeout);
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_secret(&mut self) -> Result<(), anyhow::Error> {
+        if self.link.secret.is_some() {
+            return Ok(());
+        }
+        if let Some(path) = &self.link.secret_file {
+            let secret = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read link secret from {}", path.display()))?;
+            self.link.secret = Some(Secret(secret.trim().to_owned()));
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        LinkAddress::parse(&self.link.address)?;
+        let no_secret = self
+            .link
+            .secret
+            .as_ref()
+            .is_none_or(|secret| secret.as_bytes().is_empty());
+        if no_secret && !self.link.allow_unauthenticated {
+            return Err(anyhow!(
+                "no link secret: set link.secret or link.secret_file, or set \
+                 link.allow_unauthenticated = true to accept anyone who can reach the link"
+            ));
+        }
+
+        let positive = [
+            ("context_ttl", self.context_ttl),
+            ("context_cache_entries", self.context_cache_entries as u64),
+            ("context_cache_chunks", self.context_cache_chunks as u64),
+            ("context_creations", self.context_creations as u64),
+            ("manifest_cache_max_bytes", self.manifest_cache_max_bytes),
+            ("read_buffer_size", self.read_buffer_size as u64),
+            ("reader_threads", self.reader_threads as u64),
+            ("link.max_frame_size", self.link.max_frame_size as u64),
+            ("link.handshake_timeout", self.link.handshake_timeout),
+            ("link.heartbeat_interval", self.link.heartbeat_interval),
+            ("link.missed_heartbeats", self.link.missed_heartbeats.into()),
+            ("rpc.timeout", self.rpc.timeout),
+            ("chunk_cache.max_bytes", self.chunk_cache.max_bytes),
+        ];
+        for (name, value) in positive {
+            if value == 0 {
+                return Err(anyhow!("{name} must be greater than 0"));
+            }
+        }
+
+        if self.admin_bind_address == self.bind_address {
+            return Err(anyhow!(
+                "admin_bind_address can't be the same as bind_address"
+            ));
+        }
+
+        if self.link.socket_mode > 0o777 {
+            return Err(anyhow!(
+                "link.socket_mode {:o} isn't a valid file mode",
+                self.link.socket_mode
+            ));
+        }
+
+        if !self.compression.algorithms.is_empty() && self.chunk_cache.dir.is_none() {
+            return Err(anyhow!(
+                "compression.algorithms needs chunk_cache.dir, as compressed chunks are only \
+                 served from the chunk cache"
+            ));
+        }
+
+        for (name, timeout) in &self.rpc.timeouts {
+            if !DropBoundType::VALUES
+                .iter()
+                .any(|message_type| format!("{message_type:?}") == *name)
+            {
+                return Err(anyhow!("rpc.timeouts: unknown message type {name}"));
+            }
+            if *timeout == 0 {
+                return Err(anyhow!("rpc.timeouts.{name} must be greater than 0"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/**
+Which settings changed on a reload
+*/
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadReport {
+    pub applied: Vec<&'static str>,
+    /// Changed, but only take effect after a restart
+    pub requires_restart: Vec<&'static str>,
+}
+
+impl Config {
+    /**
+    Takes the settings from `new` that are safe to change while running,
+    and keeps our own values for the rest
+    */
+    fn merge_live(&mut self, new: &Config) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        macro_rules! live {
+            ($name:literal, $($field:ident).+) => {
+                if self.$($field).+ != new.$($field).+ {
+                    self.$($field).+.clone_from(&new.$($field).+);
+                    report.applied.push($name);
+                }
+            };
+        }
+        macro_rules! restart {
+            ($name:literal, $($field:ident).+) => {
+                if self.$($field).+ != new.$($field).+ {
+                    report.requires_restart.push($name);
+                }
+            };
+        }
+
+        live!("log_level", log_level);
+        live!("context_ttl", context_ttl);
+        live!("context_cache_entries", context_cache_entries);
+        live!("context_cache_chunks", context_cache_chunks);
+        live!("manifest_cache_dir", manifest_cache_dir);
+        live!("manifest_cache_max_bytes", manifest_cache_max_bytes);
+        live!("verify_chunks", verify_chunks);
+        live!("read_buffer_size", read_buffer_size);
+        live!("speedtest_size", speedtest_size);
+        live!("shutdown_timeout", shutdown_timeout);
+        live!("link.secret", link.secret);
+        live!("link.secret_file", link.secret_file);
+        live!("link.allow_unauthenticated", link.allow_unauthenticated);
+        live!("link.max_frame_size", link.max_frame_size);
+        live!("link.handshake_timeout", link.handshake_timeout);
+        live!("link.heartbeat_interval", link.heartbeat_interval);
+        live!("link.missed_heartbeats", link.missed_heartbeats);
+        live!("rpc.timeout", rpc.timeout);
+        live!("rpc.timeouts", rpc.timeouts);
+        live!("chunk_cache.max_bytes", chunk_cache.max_bytes);
+        // Compressed chunks are only served from the chunk cache, so turning
+        // it on has to wait for the restart that turns the cache on
+        if self.chunk_cache.dir == new.chunk_cache.dir {
+            live!("compression", compression);
+        } else {
+            restart!("compression", compression);
+        }
+
+        restart!("bind_address", bind_address);
+        restart!("admin_bind_address", admin_bind_address);
+        restart!("working_directory", working_directory);
+        restart!("reader_threads", reader_threads);
+        restart!("context_creations", context_creations);
+        restart!("link.address", link.address);
+        restart!("link.socket_mode", link.socket_mode);
+        restart!("chunk_cache.dir", chunk_cache.dir);
+
+        report
+    }
+}
+
+/**
+The running configuration, which can be swapped out on reload.
+Read it with `get` each time it's used, rather than holding onto it
+*/
+pub struct SharedConfig {
+    args: Args,
+    current: watch::Sender<Arc<Config>>,
+}
+
+impl SharedConfig {
+    #[must_use]
+    pub fn new(args: Args, config: Config) -> Self {
+        Self {
+            args,
+            current: watch::Sender::new(Arc::new(config)),
+        }
+    }
+
+    #[must_use]
+    pub fn get(&self) -> Arc<Config> {
+        self.current.borrow().clone()
+    }
+
+    /**
+    Loads the configuration again from the same sources as at startup, and
+    applies what can be applied live. An invalid configuration is rejected
+    as a whole, leaving the running one untouched, and so is one that'd
+    only be valid after a restart
+    */
+    pub fn reload(&self) -> Result<ReloadReport, anyhow::Error> {
+        let new = Config::load(&self.args)?;
+
+        let mut result = Ok(ReloadReport::default());
+        self.current.send_if_modified(|current| {
+            let mut merged = Config::clone(current);
+            let report = merged.merge_live(&new);
+            if let Err(err) = merged.validate() {
+                result = Err(err.context("the configuration can't be applied without a restart"));
+                return false;
+            }
+            let changed = !report.applied.is_empty();
+            result = Ok(report);
+            if !changed {
+                return false;
+            }
+            log::set_max_level(merged.log_level);
+            *current = Arc::new(merged);
+            true
+        });
+        let report = result?;
+
+        info!("reloaded configuration, applied {:?}", report.applied);
+        if !report.requires_restart.is_empty() {
+            warn!(
+                "configuration changes to {:?} need a restart to apply",
+                report.requires_restart
+            );
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    /**
+    Parses `argv` as if `env` were the whole environment. clap would
+    otherwise read the real one, which tests can't control
+    */
+    fn parse_args(argv: &[&str], env: &[(&'static str, &'static str)]) -> Args {
+        let command = Args::command().mut_args(|arg| {
+            let value = arg.get_env().and_then(|name| {
+                env.iter()
+                    .find(|(variable, _)| name == *variable)
+                    .map(|(_, value)| *value)
+            });
+            arg.env(None).default_value(value)
+        });
+        Args::from_arg_matches(&command.get_matches_from(argv)).unwrap()
+    }
+
+    fn env_lookup(env: &[(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        |name| {
+            env.iter()
+                .find(|(variable, _)| *variable == name)
+                .map(|(_, value)| (*value).to_owned())
+        }
+    }
+
+    fn load(
+        file: &str,
+        argv: &[&str],
+        env: &[(&'static str, &'static str)],
+    ) -> Result<Config, anyhow::Error> {
+        let mut config_file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut config_file, file.as_bytes()).unwrap();
+        let path = config_file.path().to_str().unwrap().to_owned();
+
+        let mut argv = argv.to_vec();
+        argv.splice(0..0, ["torrential", "--config", &path]);
+        Config::load_with_env(&parse_args(&argv, env), env_lookup(env))
+    }
+
+    /// A setting's name, and how to make it invalid
+    type Breakage = (&'static str, fn(&mut Config));
+
+    fn unauthenticated() -> Config {
+        Config {
+            link: LinkConfig {
+                allow_unauthenticated: true,
+                ..LinkConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let file = "
+            context_ttl = 1
+            shutdown_timeout = 1
+            speedtest_size = 1
+
+            [link]
+            allow_unauthenticated = true
+
+            [rpc.timeouts]
+            VERSION_QUERY = 1
+            PING = 1
+        ";
+        let env = [
+            ("CONTEXT_TTL", "2"),
+            ("SHUTDOWN_TIMEOUT", "2"),
+            ("RPC_TIMEOUT_VERSION_QUERY", "2"),
+        ];
+        let config = load(file, &["--context-ttl", "3"], &env).unwrap();
+
+        assert_eq!(config.speedtest_size, 1);
+        assert_eq!(config.shutdown_timeout, 2);
+        assert_eq!(config.context_ttl, 3);
+        assert_eq!(
+            config.rpc.timeout_for(DropBoundType::VERSION_QUERY),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            config.rpc.timeout_for(DropBoundType::PING),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            config.rpc.timeout_for(DropBoundType::SERVER_GAMES_QUERY),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rpc_timeouts_from_the_environment() {
+        let file = "[link]\nallow_unauthenticated = true";
+        let err = load(file, &[], &[("RPC_TIMEOUT_PING", "soon")]).unwrap_err();
+        assert!(format!("{err:#}").contains("RPC_TIMEOUT_PING"), "{err:#}");
+
+        let err = load(file, &[], &[("RPC_TIMEOUT_PING", "0")]).unwrap_err();
+        assert!(format!("{err:#}").contains("rpc.timeouts.PING"), "{err:#}");
+    }
+
+    #[test]
+    fn rejects_unknown_settings_in_the_file() {
+        let err = load("contxt_ttl = 5", &[], &[]).unwrap_err();
+        assert!(format!("{err:#}").contains("contxt_ttl"), "{err:#}");
+    }
+
+    #[test]
+    fn validation_errors_name_the_setting() {
+        assert!(unauthenticated().validate().is_ok());
+
+        let cases: [Breakage; 7] = [
+            ("link.secret", |config| {
+                config.link.allow_unauthenticated = false;
+            }),
+            ("context_ttl", |config| config.context_ttl = 0),
+            ("rpc.timeouts", |config| {
+                config.rpc.timeouts.insert("NOT_A_TYPE".to_owned(), 5);
+            }),
+            ("link.socket_mode", |config| {
+                config.link.socket_mode = 0o1777;
+            }),
+            ("unsupported drop link address", |config| {
+                config.link.address = "udp://127.0.0.1:1".to_owned();
+            }),
+            ("admin_bind_address", |config| {
+                config.admin_bind_address = config.bind_address;
+            }),
+            ("chunk_cache.dir", |config| {
+                config.compression.algorithms = vec![Compression::Zstd];
+            }),
+        ];
+        for (name, break_it) in cases {
+            let mut config = unauthenticated();
+            break_it(&mut config);
+            let err = config.validate().unwrap_err();
+            assert!(err.to_string().contains(name), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn compression_waits_for_the_chunk_cache_it_needs() {
+        let mut running = unauthenticated();
+        let mut new = running.clone();
+        new.chunk_cache.dir = Some(PathBuf::from("chunks"));
+        new.compression.algorithms = vec![Compression::Zstd];
+
+        let report = running.merge_live(&new);
+
+        assert!(report.applied.is_empty());
+        assert_eq!(report.requires_restart, ["compression", "chunk_cache.dir"]);
+        assert!(running.compression.algorithms.is_empty());
+        assert!(running.validate().is_ok());
+    }
+
+    #[test]
+    fn reloads_apply_live_settings_and_report_the_rest() {
+        let mut running = unauthenticated();
+        let mut new = running.clone();
+        new.context_ttl += 1;
+        new.log_level = LevelFilter::Debug;
+        new.bind_address = SocketAddr::from(([127, 0, 0, 1], 6000));
+        new.chunk_cache.dir = Some(PathBuf::from("chunks"));
+
+        let report = running.merge_live(&new);
+
+        assert_eq!(report.applied, ["log_level", "context_ttl"]);
+        assert_eq!(report.requires_restart, ["bind_address", "chunk_cache.dir"]);
+        assert_eq!(running.context_ttl, new.context_ttl);
+        assert_eq!(running.log_level, LevelFilter::Debug);
+        assert_eq!(running.bind_address, Config::default().bind_address);
+        assert_eq!(running.chunk_cache.dir, None);
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use futures_util::{
+    FutureExt,
+    future::{BoxFuture, Shared},
+};
+use log::info;
+use reqwest::StatusCode;
+use serde::Serialize;
+use tokio::{sync::Semaphore, time};
+
+use crate::{
+    DownloadContext,
+    config::SharedConfig,
+    downloads::{
+        chunk_cache::ChunkCache,
+        invalidation::{Epochs, Invalidation},
+        manifest_cache,
+    },
+    metrics::METRICS,
+    state::AppState,
+};
+
+pub type ContextKey = (String, String);
+
+/**
+A context being created by its own task, shared by every request waiting on
+it. The task runs to the end even if they all give up
+*/
+type Creation = Shared<BoxFuture<'static, Result<Arc<DownloadContext>, StatusCode>>>;
+
+struct CachedContext {
+    context: Arc<DownloadContext>,
+    last_access: Instant,
+}
+
+/**
+Download contexts by (game, version), bounded by entry count and by the
+total number of manifest chunks held, as a stand-in for memory. When full,
+the least recently used contexts are evicted first. Contexts unused for
+`context_ttl` are dropped by `expiry_subroutine`. Contexts are handed out
+as `Arc`s, so the cache is only locked for as long as a lookup takes.
+
+Each context is only created once at a time: concurrent requests for it
+wait on the same creation, and all get its error if it fails. A creation
+carries on even if every request waiting on it goes away. Creations for
+different keys run in parallel, up to `context_creations` at once.
+
+An invalidation detaches the creations it covers, so later requests start
+afresh, and advances the keys' epochs so anything those creations would
+publish afterwards is dropped
+*/
+pub struct ContextCache {
+    config: Arc<SharedConfig>,
+    contexts: DashMap<ContextKey, CachedContext>,
+    /// Each with an ID, so a creation finishing only removes itself
+    creations: Arc<DashMap<ContextKey, (u64, Creation)>>,
+    next_creation: AtomicU64,
+    creation_permits: Arc<Semaphore>,
+    epochs: Epochs,
+    chunks: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    /// Chunk bodies of these contexts, on disk
+    chunk_cache: ChunkCache,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub entries: usize,
+    pub max_entries: usize,
+    pub chunks: usize,
+    pub max_chunks: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Contexts being created right now
+    pub creating: usize,
+}
+
+impl ContextCache {
+    #[must_use]
+    pub fn new(config: Arc<SharedConfig>) -> Self {
+        let creation_permits = Arc::new(Semaphore::new(config.get().context_creations));
+        Self {
+            chunk_cache: ChunkCache::new(config.clone()),
+            config,
+            contexts: DashMap::new(),
+            creations: Arc::new(DashMap::new()),
+            next_creation: AtomicU64::new(0),
+            creation_permits,
+            epochs: Epochs::default(),
+            chunks: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /**
+    Looks up a context, marking it as used. Expired contexts are treated as
+    missing, so a slow sweep never hands out stale ones
+    */
+    pub fn get(&self, key: &ContextKey) -> Option<Arc<DownloadContext>> {
+        let ttl = Duration::from_secs(self.config.get().context_ttl);
+        let mut cached = self.contexts.get_mut(key)?;
+        if cached.last_access.elapsed() >= ttl {
+            drop(cached);
+            self.remove(key, "expired");
+            return None;
+        }
+
+        cached.last_access = Instant::now();
+        Some(cached.context.clone())
+    }
+
+    /**
+    Returns the context for `key`, or waits for `create` to make, `insert`
+    and return it. If it's already being created, waits on that instead.
+    Requests that find the context ready count as hits, and requests that
+    have to wait count as misses
+    */
+    pub async fn get_or_create<F>(
+        &self,
+        key: ContextKey,
+        create: impl FnOnce() -> F,
+    ) -> Result<Arc<DownloadContext>, StatusCode>
+    where
+        F: Future<Output = Result<Arc<DownloadContext>, StatusCode>> + Send + 'static,
+    {
+        if let Some(context) = self.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            METRICS.context_cache.with_label_values(&["hit"]).inc();
+            return Ok(context);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        METRICS.context_cache.with_label_values(&["miss"]).inc();
+
+        let creation = self
+            .creations
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let creations = self.creations.clone();
+                let permits = self.creation_permits.clone();
+                let key = key.clone();
+                let id = self.next_creation.fetch_add(1, Ordering::Relaxed);
+                let create = create();
+                let task = tokio::spawn(async move {
+                    let result = match permits.acquire_owned().await {
+                        // Its own task too, so a panic still frees the key
+                        Ok(_permit) => tokio::spawn(create)
+                            .await
+                            .unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR)),
+                        Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+                    };
+                    creations.remove_if(&key, |_, (current, _)| *current == id);
+                    result
+                });
+                let creation = task
+                    .map(|joined| joined.unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR)))
+                    .boxed()
+                    .shared();
+                (id, creation)
+            })
+            .1
+            .clone();
+        creation.await
+    }
+
+    /**
+    Adds a freshly created context, evicting the least recently used ones
+    to make room. A context bigger than the whole chunk budget is still
+    kept, as it's about to be served from. One invalidated since its
+    creation started is only handed back, not kept
+    */
+    pub fn insert(&self, key: &ContextKey, context: DownloadContext) -> Arc<DownloadContext> {
+        let context = Arc::new(context);
+        if !self.epochs.is_current(key, context.epoch) {
+            return context;
+        }
+        self.take(key);
+        self.make_room(context.chunk_count());
+
+        self.chunks
+            .fetch_add(context.chunk_count(), Ordering::Relaxed);
+        self.contexts.insert(
+            key.clone(),
+            CachedContext {
+                context: context.clone(),
+                last_access: Instant::now(),
+            },
+        );
+
+        // Invalidated in between, after it had already looked for this one
+        if !self.epochs.is_current(key, context.epoch)
+            && let Some((_, cached)) = self
+                .contexts
+                .remove_if(key, |_, cached| Arc::ptr_eq(&cached.context, &context))
+        {
+            self.chunks
+                .fetch_sub(cached.context.chunk_count(), Ordering::Relaxed);
+        }
+        context
+    }
+
+    /**
+    Removes a context, returning whether there was one
+    */
+    pub fn remove(&self, key: &ContextKey, reason: &str) -> bool {
+        if self.take(key).is_none() {
+            return false;
+        }
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        METRICS.context_evictions.with_label_values(&[reason]).inc();
+        info!("evicted {reason} context: {key:?}");
+        true
+    }
+
+    fn take(&self, key: &ContextKey) -> Option<Arc<DownloadContext>> {
+        let (_, cached) = self.contexts.remove(key)?;
+        self.chunks
+            .fetch_sub(cached.context.chunk_count(), Ordering::Relaxed);
+        Some(cached.context)
+    }
+
+    /**
+    Drops every context `target` covers, along with their cached manifests,
+    returning how many of each there were
+    */
+    pub async fn invalidate(&self, target: &Invalidation) -> (usize, usize) {
+        // Before anything is removed, so whatever's in flight can't put it back
+        self.epochs.advance(target);
+        self.creations.retain(|key, _| !target.matches(key));
+
+        // Disk first, so a request in between can't load the old manifest back
+        let config = self.config.get();
+        let manifests = manifest_cache::invalidate(&config.manifest_cache_dir, target).await;
+        self.chunk_cache.invalidate(target).await;
+
+        let keys = self
+            .contexts
+            .iter()
+            .filter(|cached| target.matches(cached.key()))
+            .map(|cached| cached.key().clone())
+            .collect::<Vec<_>>();
+        let contexts = keys
+            .iter()
+            .filter(|key| self.remove(key, "invalidated"))
+            .count();
+
+        (contexts, manifests)
+    }
+
+    #[must_use]
+    pub fn epochs(&self) -> &Epochs {
+        &self.epochs
+    }
+
+    #[must_use]
+    pub fn chunk_cache(&self) -> &ChunkCache {
+        &self.chunk_cache
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.contexts.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.contexts.is_empty()
+    }
+
+    #[must_use]
+    pub fn chunks(&self) -> usize {
+        self.chunks.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> CacheStats {
+        let config = self.config.get();
+        CacheStats {
+            entries: self.len(),
+            max_entries: config.context_cache_entries,
+            chunks: self.chunks(),
+            max_chunks: config.context_cache_chunks,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            creating: self.creations.len(),
+        }
+    }
+
+    /**
+    Evicts least recently used contexts until one with `incoming` chunks
+    fits. With `incoming` as 0, just brings the cache back within its
+    limits, e.g. after they were lowered by a reload
+    */
+    fn make_room(&self, incoming: usize) {
+        let config = self.config.get();
+        let slots = usize::from(incoming > 0);
+
+        while !self.contexts.is_empty()
+            && (self.len() + slots > config.context_cache_entries
+                || self.chunks() + incoming > config.context_cache_chunks)
+        {
+            let Some(oldest) = self.least_recently_used() else {
+                break;
+            };
+            self.remove(&oldest, "capacity");
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<ContextKey> {
+        self.contexts
+            .iter()
+            .min_by_key(|cached| cached.last_access)
+            .map(|cached| cached.key().clone())
+    }
+
+    fn remove_expired(&self) {
+        let ttl = Duration::from_secs(self.config.get().context_ttl);
+        let expired = self
+            .contexts
+            .iter()
+            .filter(|cached| cached.last_access.elapsed() >= ttl)
+            .map(|cached| cached.key().clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.remove(&key, "expired");
+        }
+    }
+}
+
+/**
+Long-lived subroutine that drops expired contexts, and trims the cache
+if its limits were lowered by a reload
+*/
+pub async fn expiry_subroutine(state: Arc<AppState>) -> ! {
+    let mut interval = time::interval(Duration::from_mins(1));
+    loop {
+        interval.tick().await;
+        state.context_cache.remove_expired();
+        state.context_cache.make_room(0);
+        state.context_cache.chunk_cache().make_room().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::config::{Args, Config};
+
+    fn cache() -> ContextCache {
+        ContextCache::new(Arc::new(SharedConfig::new(
+            Args::default(),
+            Config::default(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn creation_outlives_its_waiters() {
+        let cache = cache();
+        let (release, released) = oneshot::channel::<()>();
+        let (finished, mut has_finished) = oneshot::channel();
+        let key = ("game".to_owned(), "v1".to_owned());
+
+        let waiter = cache.get_or_create(key.clone(), || async move {
+            let _ = released.await;
+            let _ = finished.send(());
+            Err(StatusCode::NOT_FOUND)
+        });
+        assert!(
+            time::timeout(Duration::from_millis(10), waiter)
+                .await
+                .is_err()
+        );
+        assert_eq!(cache.stats().creating, 1);
+
+        release.send(()).unwrap();
+        time::timeout(Duration::from_secs(1), &mut has_finished)
+            .await
+            .unwrap()
+            .unwrap();
+        time::timeout(Duration::from_secs(1), async {
+            while cache.stats().creating > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiters_share_one_creation() {
+        let cache = cache();
+        let key = ("game".to_owned(), "v1".to_owned());
+        let created = Arc::new(AtomicUsize::new(0));
+
+        let create = || {
+            let created = created.clone();
+            async move {
+                created.fetch_add(1, Ordering::Relaxed);
+                time::sleep(Duration::from_millis(20)).await;
+                Err(StatusCode::NOT_FOUND)
+            }
+        };
+        let (first, second) = tokio::join!(
+            cache.get_or_create(key.clone(), create),
+            cache.get_or_create(key.clone(), create),
+        );
+
+        assert_eq!(first.err(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(second.err(), Some(StatusCode::NOT_FOUND));
+        assert_eq!(created.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,636 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Instant, SystemTime},
+};
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::anyhow;
+use dashmap::{DashMap, DashSet};
+use droplet_rs::manifest::ChunkData;
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::Semaphore,
+};
+use tokio_util::io::StreamReader;
+
+use crate::{
+    DownloadContext,
+    config::SharedConfig,
+    downloads::{
+        compression::Encoding,
+        encrypt::EncryptingStream,
+        invalidation::Invalidation,
+        manifest_cache::is_safe,
+        range::ByteRange,
+        serve::{Aes128Ctr64LE, acquire_file_permits, plaintext_stream},
+        verify::ChunkCheck,
+    },
+    metrics::METRICS,
+    state::AppState,
+};
+
+/**
+Chunks written to the cache at once. Misses past that aren't cached, a
+later request for the chunk will try again
+*/
+const FILLS: usize = 4;
+
+/**
+A chunk as encrypted for one manifest, either as is or compressed. The
+checksum is part of the key, so a chunk whose contents change is never
+served from an old copy. The game ID in request paths isn't checked by
+Drop, so it isn't part of it either
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub version_id: String,
+    pub chunk_id: String,
+    pub checksum: String,
+    pub encoding: Option<Encoding>,
+}
+
+impl ChunkKey {
+    #[must_use]
+    pub fn new(
+        context: &DownloadContext,
+        chunk_id: &str,
+        chunk: &ChunkData,
+        encoding: Option<Encoding>,
+    ) -> Self {
+        Self {
+            version_id: context.version_name.clone(),
+            chunk_id: chunk_id.to_owned(),
+            checksum: chunk.checksum.to_ascii_lowercase(),
+            encoding,
+        }
+    }
+
+    /**
+    Stored as `<version id>/<chunk id>.<checksum>`, with `.<encoding tag>`
+    after it if it's compressed
+    */
+    fn path(&self, dir: &Path) -> Option<PathBuf> {
+        let ids = [&self.version_id, &self.chunk_id, &self.checksum];
+        ids.iter().all(|id| is_safe(id)).then(|| {
+            let mut name = format!("{}.{}", self.chunk_id, self.checksum);
+            if let Some(encoding) = self.encoding {
+                name = format!("{name}.{}", encoding.tag());
+            }
+            dir.join(&self.version_id).join(name)
+        })
+    }
+
+    fn from_path(path: &Path) -> Option<Self> {
+        let mut parts = path.file_name()?.to_str()?.split('.');
+        let (chunk_id, checksum) = (parts.next()?, parts.next()?);
+        let encoding = match parts.next() {
+            Some(tag) => Some(Encoding::from_tag(tag)?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        let key = Self {
+            version_id: path.parent()?.file_name()?.to_str()?.to_owned(),
+            chunk_id: chunk_id.to_owned(),
+            checksum: checksum.to_owned(),
+            encoding,
+        };
+        // Rejects anything we wouldn't have written ourselves
+        key.path(Path::new("")).is_some().then_some(key)
+    }
+
+    /**
+    The IV the cached bytes are encrypted with. See `Encoding`
+    */
+    #[must_use]
+    pub fn iv(&self, chunk: &ChunkData) -> [u8; 16] {
+        self.encoding
+            .map_or(chunk.iv, |encoding| encoding.iv(&chunk.iv))
+    }
+}
+
+struct CachedChunk {
+    size: u64,
+    last_access: Instant,
+}
+
+/**
+Encrypted chunk bodies on local disk, exactly as they're sent for a full
+download, compressed or not, bounded by `chunk_cache.max_bytes`. When
+full, the least recently used chunks are removed first. Chunks are added
+in the background after a miss on a full chunk, and only if what was
+written decrypts (and decompresses) to their checksum
+*/
+pub struct ChunkCache {
+    config: Arc<SharedConfig>,
+    /// Fixed at startup, as the index is only valid for one directory
+    dir: Option<PathBuf>,
+    chunks: DashMap<ChunkKey, CachedChunk>,
+    filling: DashSet<ChunkKey>,
+    fills: Arc<Semaphore>,
+    bytes: AtomicU64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkCacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl ChunkCache {
+    #[must_use]
+    pub fn new(config: Arc<SharedConfig>) -> Self {
+        let dir = config.get().chunk_cache.dir.clone();
+        Self {
+            config,
+            dir,
+            chunks: DashMap::new(),
+            filling: DashSet::new(),
+            fills: Arc::new(Semaphore::new(FILLS)),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /**
+    Indexes the chunks already on disk, oldest modified first to go
+    */
+    pub async fn load(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let now = (Instant::now(), SystemTime::now());
+        let mut versions = match tokio::fs::read_dir(dir).await {
+            Ok(versions) => versions,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("failed to read chunk cache {}: {err}", dir.display());
+                }
+                return;
+            }
+        };
+
+        while let Ok(Some(version)) = versions.next_entry().await {
+            let Ok(mut files) = tokio::fs::read_dir(version.path()).await else {
+                continue;
+            };
+            while let Ok(Some(file)) = files.next_entry().await {
+                self.load_file(&file.path(), now).await;
+            }
+        }
+
+        info!(
+            "found {} cached chunks ({} bytes) in {}",
+            self.chunks.len(),
+            self.bytes(),
+            dir.display()
+        );
+        self.make_room().await;
+    }
+
+    async fn load_file(&self, path: &Path, (now, system_now): (Instant, SystemTime)) {
+        let Some(key) = ChunkKey::from_path(path) else {
+            // Left over from a fill that didn't finish
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                let _ = tokio::fs::remove_file(path).await;
+            }
+            return;
+        };
+        let Ok(metadata) = tokio::fs::metadata(path).await else {
+            return;
+        };
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| system_now.duration_since(modified).ok())
+            .unwrap_or_default();
+        self.insert(key, metadata.len(), now.checked_sub(age).unwrap_or(now));
+    }
+
+    /**
+    Opens a cached chunk, marking it as used
+    */
+    pub async fn open(&self, key: &ChunkKey) -> Option<(File, u64)> {
+        let path = key.path(self.dir.as_ref()?)?;
+        let size = {
+            let Some(mut cached) = self.chunks.get_mut(key) else {
+                METRICS.chunk_cache.with_label_values(&["miss"]).inc();
+                return None;
+            };
+            cached.last_access = Instant::now();
+            cached.size
+        };
+
+        match File::open(&path).await {
+            Ok(file) => {
+                METRICS.chunk_cache.with_label_values(&["hit"]).inc();
+                Some((file, size))
+            }
+            Err(err) => {
+                warn!("cached chunk {} is gone: {err}", path.display());
+                METRICS.chunk_cache.with_label_values(&["miss"]).inc();
+                self.forget(key);
+                None
+            }
+        }
+    }
+
+    /**
+    Whether a chunk is cached, without counting it as a hit or a miss
+    */
+    #[must_use]
+    pub fn contains(&self, key: &ChunkKey) -> bool {
+        self.chunks.contains_key(key)
+    }
+
+    fn insert(&self, key: ChunkKey, size: u64, last_access: Instant) {
+        let previous = self.chunks.insert(key, CachedChunk { size, last_access });
+        if let Some(previous) = previous {
+            self.bytes.fetch_sub(previous.size, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn forget(&self, key: &ChunkKey) -> bool {
+        let Some((_, cached)) = self.chunks.remove(key) else {
+            return false;
+        };
+        self.bytes.fetch_sub(cached.size, Ordering::Relaxed);
+        true
+    }
+
+    /**
+    Removes a chunk from the index and the disk. Responses already
+    reading it keep going, as the file stays around until they close it
+    */
+    pub async fn remove(&self, key: &ChunkKey) {
+        if !self.forget(key) {
+            return;
+        }
+        if let Some(path) = self.dir.as_deref().and_then(|dir| key.path(dir))
+            && let Err(err) = tokio::fs::remove_file(&path).await
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("failed to remove cached chunk {}: {err}", path.display());
+        }
+    }
+
+    /**
+    Removes every chunk `target` covers, returning how many there were.
+    Which game a version belongs to isn't known, so invalidating a game
+    empties the cache
+    */
+    pub async fn invalidate(&self, target: &Invalidation) -> usize {
+        let Some(dir) = &self.dir else {
+            return 0;
+        };
+        let keys = self
+            .chunks
+            .iter()
+            .filter(|cached| match target {
+                Invalidation::Version(_, version_id) => &cached.key().version_id == version_id,
+                Invalidation::Game(_) | Invalidation::All => true,
+            })
+            .map(|cached| cached.key().clone())
+            .collect::<Vec<_>>();
+        let removed = keys.iter().filter(|key| self.forget(key)).count();
+
+        target.remove_from(dir).await;
+        removed
+    }
+
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> ChunkCacheStats {
+        ChunkCacheStats {
+            entries: self.chunks.len(),
+            bytes: self.bytes(),
+            max_bytes: self.config.get().chunk_cache.max_bytes,
+        }
+    }
+
+    /**
+    Removes least recently used chunks until the cache is within
+    `chunk_cache.max_bytes`
+    */
+    pub async fn make_room(&self) {
+        let max_bytes = self.config.get().chunk_cache.max_bytes;
+        while self.bytes() > max_bytes {
+            let Some(oldest) = self
+                .chunks
+                .iter()
+                .min_by_key(|cached| cached.last_access)
+                .map(|cached| cached.key().clone())
+            else {
+                break;
+            };
+            self.remove(&oldest).await;
+            METRICS.chunk_cache.with_label_values(&["eviction"]).inc();
+        }
+    }
+}
+
+/**
+Encrypts a chunk into the cache in the background, compressed with
+`encoding` if there is one, unless that's already happening or `FILLS`
+others are. Nothing is kept if the version was invalidated since
+`context` was created, and shutdown stops it
+*/
+pub fn fill(
+    state: &Arc<AppState>,
+    context: Arc<DownloadContext>,
+    chunk_id: String,
+    encoding: Option<Encoding>,
+) {
+    let cache = state.context_cache.chunk_cache();
+    let Some(chunk) = context.manifest.chunks.get(&chunk_id) else {
+        return;
+    };
+    let key = ChunkKey::new(&context, &chunk_id, chunk, encoding);
+    let Some(path) = cache.dir.as_deref().and_then(|dir| key.path(dir)) else {
+        return;
+    };
+    let Ok(permit) = cache.fills.clone().try_acquire_owned() else {
+        return;
+    };
+    if !cache.filling.insert(key.clone()) {
+        return;
+    }
+
+    let state = state.clone();
+    let server = state.server.clone();
+    server.spawn_tracked(async move {
+        let _permit = permit;
+        let cache = state.context_cache.chunk_cache();
+        let version = (context.game_id.clone(), key.version_id.clone());
+        let is_current = || {
+            state
+                .context_cache
+                .epochs()
+                .is_current(&version, context.epoch)
+        };
+        let temporary = temporary_path(&path);
+        let written = tokio::select! {
+            written = write_encrypted(&state, &context, &key, &temporary) => written,
+            () = state.server.shutting_down() => Err(anyhow!("shutting down")),
+        };
+        match written {
+            Ok(_) if !is_current() => {
+                let _ = tokio::fs::remove_file(&temporary).await;
+            }
+            Ok(size) => match tokio::fs::rename(&temporary, &path).await {
+                Ok(()) => {
+                    cache.insert(key.clone(), size, Instant::now());
+                    // Invalidated in between, possibly after it had been removed
+                    if is_current() {
+                        cache.make_room().await;
+                    } else {
+                        cache.remove(&key).await;
+                    }
+                }
+                Err(err) => warn!("failed to move {} into place: {err}", path.display()),
+            },
+            Err(err) => {
+                warn!("failed to cache chunk {chunk_id}: {err:#}");
+                let _ = tokio::fs::remove_file(&temporary).await;
+            }
+        }
+        cache.filling.remove(&key);
+    });
+}
+
+/**
+A name next to `path` no other fill uses. Ends in `.tmp`, so `load` can
+clear up after one that didn't finish
+*/
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    path.with_file_name(name)
+}
+
+/**
+Writes a chunk's encrypted bytes to `path`, returning how many there were
+if reading them back gives the chunk's checksum
+*/
+async fn write_encrypted(
+    state: &Arc<AppState>,
+    context: &DownloadContext,
+    key: &ChunkKey,
+    path: &Path,
+) -> Result<u64, anyhow::Error> {
+    let chunk_id = &key.chunk_id;
+    let chunk = context
+        .manifest
+        .chunks
+        .get(chunk_id)
+        .ok_or_else(|| anyhow!("chunk {chunk_id} isn't in the manifest"))?;
+    let full = ByteRange::full(chunk.files.iter().map(|file| file.length).sum());
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let _permit = acquire_file_permits(chunk, &[full])
+        .await
+        .map_err(|status| anyhow!("no file permits: {status}"))?;
+    let plaintext = plaintext_stream(context, chunk, full, None)
+        .await
+        .map_err(|status| anyhow!("failed to open files: {status}"))?;
+    let plaintext = StreamReader::new(plaintext);
+    let mut reader: Box<dyn AsyncRead + Send + Unpin> = match key.encoding {
+        Some(encoding) => encoding.compression.encoder(plaintext, encoding.level),
+        None => Box::new(plaintext),
+    };
+    let cipher = || Aes128Ctr64LE::new(&context.manifest.key.into(), &key.iv(chunk).into());
+
+    let mut output = File::create(path).await?;
+    let mut encrypt = cipher();
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let data = &mut buffer[..read];
+        encrypt.apply_keystream(data);
+        output.write_all(data).await?;
+        size += read as u64;
+    }
+    output.sync_all().await?;
+    drop(output);
+
+    // What's checked is what's served, rather than what was read
+    let written = StreamReader::new(EncryptingStream::new(
+        [File::open(path).await?],
+        cipher(),
+        buffer.len(),
+    ));
+    let mut reader: Box<dyn AsyncRead + Send + Unpin> = match key.encoding {
+        Some(encoding) => encoding.compression.decoder(written),
+        None => Box::new(written),
+    };
+    let mut hasher = Sha256::new();
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    let check = ChunkCheck::new(state.server.clone(), context, chunk_id.clone(), chunk);
+    if check.finish(hasher, "cache") {
+        Ok(size)
+    } else {
+        Err(anyhow!("chunk doesn't match its checksum"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        config::{Args, ChunkCacheConfig, Config},
+        downloads::compression::Compression,
+    };
+
+    fn cache(dir: &Path, max_bytes: u64) -> ChunkCache {
+        let config = Config {
+            chunk_cache: ChunkCacheConfig {
+                dir: Some(dir.to_owned()),
+                max_bytes,
+            },
+            ..Default::default()
+        };
+        ChunkCache::new(Arc::new(SharedConfig::new(Args::default(), config)))
+    }
+
+    fn key(version_id: &str, chunk_id: &str) -> ChunkKey {
+        ChunkKey {
+            version_id: version_id.to_owned(),
+            chunk_id: chunk_id.to_owned(),
+            checksum: "abcd".to_owned(),
+            encoding: None,
+        }
+    }
+
+    /**
+    Writes `size` bytes where `key` goes, and indexes them as last used
+    `age` ago
+    */
+    async fn put(cache: &ChunkCache, key: &ChunkKey, size: usize, age: Duration) {
+        let path = key.path(cache.dir.as_ref().unwrap()).unwrap();
+        tokio::fs::create_dir_all(path.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&path, vec![0; size]).await.unwrap();
+        cache.insert(key.clone(), size as u64, Instant::now() - age);
+    }
+
+    #[tokio::test]
+    async fn finds_what_was_written_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let compressed = ChunkKey {
+            encoding: Some(Encoding {
+                compression: Compression::Zstd,
+                level: None,
+            }),
+            ..key("v1", "c1")
+        };
+        put(&cache(dir.path(), 100), &key("v1", "c1"), 4, Duration::ZERO).await;
+        put(&cache(dir.path(), 100), &compressed, 3, Duration::ZERO).await;
+        let version_dir = dir.path().join("v1");
+        tokio::fs::write(version_dir.join("c2.abcd.0123.tmp"), "half")
+            .await
+            .unwrap();
+        tokio::fs::write(version_dir.join("notes"), "not ours")
+            .await
+            .unwrap();
+
+        let cache = cache(dir.path(), 100);
+        cache.load().await;
+
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.bytes(), 7);
+        assert_eq!(cache.open(&key("v1", "c1")).await.unwrap().1, 4);
+        assert_eq!(cache.open(&compressed).await.unwrap().1, 3);
+        assert!(cache.open(&key("v1", "c2")).await.is_none());
+        assert!(cache.open(&key("v2", "c1")).await.is_none());
+        assert!(!version_dir.join("c2.abcd.0123.tmp").exists());
+        assert!(version_dir.join("notes").exists());
+    }
+
+    #[tokio::test]
+    async fn forgets_chunks_whose_file_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 100);
+        put(&cache, &key("v1", "c1"), 4, Duration::ZERO).await;
+        tokio::fs::remove_dir_all(dir.path().join("v1"))
+            .await
+            .unwrap();
+
+        assert!(cache.open(&key("v1", "c1")).await.is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_past_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 10);
+        put(&cache, &key("v1", "c1"), 4, Duration::from_secs(30)).await;
+        put(&cache, &key("v1", "c2"), 4, Duration::from_secs(20)).await;
+        put(&cache, &key("v1", "c3"), 4, Duration::from_secs(10)).await;
+        // Used most recently now, so it outlives the others
+        cache.open(&key("v1", "c1")).await.unwrap();
+
+        cache.make_room().await;
+
+        assert_eq!(cache.bytes(), 8);
+        assert!(cache.open(&key("v1", "c2")).await.is_none());
+        assert!(cache.open(&key("v1", "c1")).await.is_some());
+        assert!(cache.open(&key("v1", "c3")).await.is_some());
+        let path = key("v1", "c2").path(dir.path()).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn invalidates_versions_by_id_and_games_as_a_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(dir.path(), 100);
+        for (version_id, chunk_id) in [("v1", "c1"), ("v1", "c2"), ("v2", "c1")] {
+            put(&cache, &key(version_id, chunk_id), 4, Duration::ZERO).await;
+        }
+
+        let version = Invalidation::Version("any-game".to_owned(), "v1".to_owned());
+        assert_eq!(cache.invalidate(&version).await, 2);
+        assert!(!dir.path().join("v1").exists());
+        assert!(cache.open(&key("v2", "c1")).await.is_some());
+
+        let game = Invalidation::Game("any-game".to_owned());
+        assert_eq!(cache.invalidate(&game).await, 1);
+        assert!(!dir.path().join("v2").exists());
+        assert_eq!(cache.bytes(), 0);
+    }
+}
@@ -0,0 +1,213 @@
+use async_compression::{
+    Level,
+    tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder},
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufRead, AsyncRead};
+
+/**
+How a chunk can be compressed. Chunks are compressed before they're
+encrypted, so the keystream starts at the first compressed byte, and
+clients decrypt the whole response before decompressing it. See
+`Encoding` for the IV that's encrypted with
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [Compression::Zstd, Compression::Gzip]
+            .into_iter()
+            .find(|compression| compression.name() == name)
+    }
+
+    /**
+    Picks the first of `offered` that `accepted` lists, in the syntax of
+    `Accept-Encoding`. Clients have to name it, as `*` would also cover
+    clients that can't undo it after decrypting
+    */
+    #[must_use]
+    pub fn negotiate(offered: &[Compression], accepted: &str) -> Option<Self> {
+        let accepted = accepted
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';');
+                let name = params.next()?.trim();
+                let refused = params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|quality| quality.trim().parse::<f32>().ok())
+                        .is_some_and(|quality| quality <= 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect::<Vec<_>>();
+
+        offered.iter().copied().find(|compression| {
+            accepted
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(compression.name()))
+        })
+    }
+
+    fn level(level: Option<i32>) -> Level {
+        level.map_or(Level::Default, Level::Precise)
+    }
+
+    /**
+    Compresses everything read from `reader`
+    */
+    pub fn encoder<R>(self, reader: R, level: Option<i32>) -> Box<dyn AsyncRead + Send + Unpin>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        let level = Self::level(level);
+        match self {
+            Compression::Zstd => Box::new(ZstdEncoder::with_quality(reader, level)),
+            Compression::Gzip => Box::new(GzipEncoder::with_quality(reader, level)),
+        }
+    }
+
+    /**
+    Decompresses everything read from `reader`
+    */
+    pub fn decoder<R>(self, reader: R) -> Box<dyn AsyncRead + Send + Unpin>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        match self {
+            Compression::Zstd => Box::new(ZstdDecoder::new(reader)),
+            Compression::Gzip => Box::new(GzipDecoder::new(reader)),
+        }
+    }
+}
+
+/**
+A compression at a given level, which together decide the bytes a chunk
+compresses to. Each encoding of a chunk is encrypted with its own IV,
+derived from the chunk's, so no two different bodies ever share a
+keystream: the first 16 bytes of
+`SHA-256("torrential chunk encoding" || chunk IV || tag)`
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Encoding {
+    pub compression: Compression,
+    pub level: Option<i32>,
+}
+
+impl Encoding {
+    /**
+    `zstd` at the default level, otherwise the level appended, e.g. `zstd19`
+    or `zstd-5`
+    */
+    #[must_use]
+    pub fn tag(self) -> String {
+        match self.level {
+            Some(level) => format!("{}{level}", self.compression.name()),
+            None => self.compression.name().to_owned(),
+        }
+    }
+
+    #[must_use]
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let split = tag
+            .find(|c: char| c == '-' || c.is_ascii_digit())
+            .unwrap_or(tag.len());
+        let (name, level) = tag.split_at(split);
+        Some(Self {
+            compression: Compression::from_name(name)?,
+            level: match level {
+                "" => None,
+                level => Some(level.parse().ok()?),
+            },
+        })
+    }
+
+    #[must_use]
+    pub fn iv(self, chunk_iv: &[u8; 16]) -> [u8; 16] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"torrential chunk encoding");
+        hasher.update(chunk_iv);
+        hasher.update(self.tag().as_bytes());
+        let mut iv = [0; 16];
+        iv.copy_from_slice(&hasher.finalize()[..16]);
+        iv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_needs_the_name() {
+        let offered = [Compression::Zstd, Compression::Gzip];
+        assert_eq!(
+            Compression::negotiate(&offered, "gzip, zstd"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::negotiate(&offered, "zstd;q=0, gzip"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(Compression::negotiate(&offered, "*"), None);
+        assert_eq!(Compression::negotiate(&[], "zstd"), None);
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        for level in [None, Some(0), Some(19), Some(-5)] {
+            for compression in [Compression::Zstd, Compression::Gzip] {
+                let encoding = Encoding { compression, level };
+                assert_eq!(Encoding::from_tag(&encoding.tag()), Some(encoding));
+            }
+        }
+        assert_eq!(Encoding::from_tag("brotli"), None);
+        assert_eq!(Encoding::from_tag("zstd-"), None);
+    }
+
+    #[test]
+    fn encodings_get_their_own_iv() {
+        let chunk_iv = [7; 16];
+        let zstd = Encoding {
+            compression: Compression::Zstd,
+            level: None,
+        };
+        let gzip = Encoding {
+            compression: Compression::Gzip,
+            level: None,
+        };
+        let zstd19 = Encoding {
+            level: Some(19),
+            ..zstd
+        };
+
+        let ivs = [
+            chunk_iv,
+            zstd.iv(&chunk_iv),
+            gzip.iv(&chunk_iv),
+            zstd19.iv(&chunk_iv),
+        ];
+        for (i, a) in ivs.iter().enumerate() {
+            for b in &ivs[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(zstd.iv(&chunk_iv), zstd.iv(&chunk_iv));
+    }
+}
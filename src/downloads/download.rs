@@ -1,75 +1,300 @@
-use std::{path::PathBuf, time::Instant};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
+use anyhow::anyhow;
+use dashmap::DashSet;
 use droplet_rs::{
     manifest::Manifest,
-    versions::{create_backend_constructor, types::VersionBackend},
+    versions::{
+        create_backend_constructor,
+        types::{MinimumFileObject, VersionBackend, VersionFile},
+    },
 };
-use log::warn;
+use log::{info, warn};
 use reqwest::StatusCode;
 use serde_json::Value;
+use tokio::time;
 
 use crate::{
     conversions::convert_protobuf_manifest,
+    downloads::{
+        cache::ContextKey,
+        manifest_cache::{self, PersistedManifest, manifest_checksum},
+    },
     proto::version::{VersionResponse, version_response::library_source::LibraryBackend},
-    server::download::fetch_version_data,
+    server::{download::fetch_version_data, pending::RequestError},
     state::AppState,
     util::ErrorOption,
 };
 
+const REVALIDATE_ATTEMPTS: u32 = 8;
+const REVALIDATE_BACKOFF: Duration = Duration::from_secs(1);
+const REVALIDATE_MAX_BACKOFF: Duration = Duration::from_mins(1);
+
+/**
+Versions whose cached manifests are being checked against Drop, so building
+their context again doesn't start another check
+*/
+static REVALIDATING: LazyLock<DashSet<ContextKey>> = LazyLock::new(DashSet::new);
+
+/**
+Everything needed to serve chunks for a version. Immutable once created,
+so it's shared between requests rather than locked
+*/
 pub struct DownloadContext {
-    pub(crate) manifest: Manifest,
-    pub(crate) backend: Box<dyn VersionBackend + Send + Sync + 'static>,
-    last_access: Instant,
+    pub(crate) game_id: String,
+    pub(crate) version_name: String,
+    pub(crate) manifest: Arc<Manifest>,
+    /// Epoch of the version when creating this started. See `Epochs`
+    pub(crate) epoch: u64,
+    backend: Box<dyn VersionBackend + Send + Sync + 'static>,
 }
 impl DownloadContext {
-    #[must_use] 
-    pub fn last_access(&self) -> Instant {
-        self.last_access
+    /**
+    Opens `relative_filename` between `start` and `end`. Backends need
+    `&mut` to read, but are cheap to clone, so each reader gets its own
+    */
+    pub async fn reader(
+        &self,
+        relative_filename: String,
+        start: u64,
+        end: u64,
+    ) -> Result<Box<dyn MinimumFileObject>, anyhow::Error> {
+        let mut backend = self.backend.clone();
+        backend
+            .reader(
+                &VersionFile {
+                    relative_filename,
+                    permission: 0,
+                    size: 0,
+                },
+                start,
+                end,
+            )
+            .await
     }
-    pub fn reset_last_access(&mut self) {
-        self.last_access = Instant::now();
+    /**
+    Rough size of the context, used to bound the cache
+    */
+    #[must_use]
+    pub fn chunk_count(&self) -> usize {
+        self.manifest.chunks.len()
     }
 }
 
+/**
+Builds a context from the cached manifest if there is one, otherwise from
+Drop's. `epoch` is the version's epoch from before anything was read
+*/
 pub async fn create_download_context(
-    app_state: &AppState,
+    app_state: &Arc<AppState>,
     game_id: String,
     version_name: String,
+    epoch: u64,
 ) -> Result<DownloadContext, ErrorOption> {
-    let version_data = fetch_version_data(app_state, game_id, version_name.clone()).await?;
+    let config = app_state.config.get();
+    let cache_dir = config.manifest_cache_dir.clone();
+    let key = (game_id.clone(), version_name.clone());
+    let epochs = app_state.context_cache.epochs();
 
-    let backend = create_backend(&version_data)?;
+    if let Some(persisted) = manifest_cache::load(&cache_dir, &version_name).await {
+        let checksum = persisted.checksum.clone();
+        let version_path = persisted.version_path.clone();
+        match build_context(app_state, key.clone(), persisted, epoch) {
+            Ok(context) => {
+                info!("using cached manifest for {version_name}");
+                spawn_revalidate(app_state, key, checksum, version_path, epoch);
+                return Ok(context);
+            }
+            Err(_) => warn!("cached manifest for {version_name} is unusable, asking drop"),
+        }
+    }
 
-    let download_context = DownloadContext {
-        manifest: convert_protobuf_manifest(version_data.manifest.unwrap()),
-        backend,
-        last_access: Instant::now(),
+    let version_data = fetch_version_data(app_state, game_id.clone(), version_name.clone()).await?;
+    let persisted = persisted_from_response(version_name.clone(), version_data)?;
+    let is_current = || epochs.is_current(&key, epoch);
+    if let Err(err) = manifest_cache::store(
+        &cache_dir,
+        config.manifest_cache_max_bytes,
+        &persisted,
+        is_current,
+    )
+    .await
+    {
+        warn!("failed to cache manifest for {version_name}: {err:#}");
+    }
+
+    Ok(build_context(app_state, key, persisted, epoch)?)
+}
+
+fn spawn_revalidate(
+    state: &Arc<AppState>,
+    key: ContextKey,
+    checksum: String,
+    version_path: PathBuf,
+    epoch: u64,
+) {
+    if !REVALIDATING.insert(key.clone()) {
+        return;
+    }
+    let app_state = state.clone();
+    state.server.spawn_tracked(async move {
+        tokio::select! {
+            () = revalidate(app_state.clone(), key.clone(), checksum, version_path, epoch) => {}
+            () = app_state.server.shutting_down() => {}
+        }
+        REVALIDATING.remove(&key);
+    });
+}
+
+/**
+Checks a manifest loaded from the cache against Drop, once it's reachable.
+If Drop has a different one, the cached copy is replaced and the context
+dropped, so the next request picks up the new one. Failed queries are
+retried with backoff a few times before the cached copy is trusted as is
+*/
+async fn revalidate(
+    state: Arc<AppState>,
+    key: ContextKey,
+    checksum: String,
+    version_path: PathBuf,
+    epoch: u64,
+) {
+    let (game_id, version_name) = key.clone();
+    let config = state.config.get();
+    let cache_dir = config.manifest_cache_dir.clone();
+
+    let mut backoff = REVALIDATE_BACKOFF;
+    let mut attempt = 1;
+    let version_data = loop {
+        state.server.connected().await;
+        match fetch_version_data(&state, game_id.clone(), version_name.clone()).await {
+            Ok(version_data) => break version_data,
+            Err(err) => match err.request_error() {
+                // Nothing to check against, so trust what we have
+                Some(RequestError::Unsupported { .. }) => return,
+                Some(_) if attempt < REVALIDATE_ATTEMPTS => {
+                    info!(
+                        "couldn't check cached manifest for {version_name}, retrying in {backoff:?}"
+                    );
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(REVALIDATE_MAX_BACKOFF);
+                    attempt += 1;
+                }
+                Some(_) => {
+                    warn!(
+                        "gave up checking cached manifest for {version_name} against drop: {err:?}"
+                    );
+                    return;
+                }
+                None => {
+                    warn!("drop rejected cached version {version_name}, dropping it: {err:?}");
+                    manifest_cache::remove(&cache_dir, &version_name).await;
+                    state.context_cache.remove(&key, "stale");
+                    return;
+                }
+            },
+        }
     };
 
-    Ok(download_context)
+    let epochs = state.context_cache.epochs();
+    let is_current = || epochs.is_current(&key, epoch);
+    match persisted_from_response(version_name.clone(), version_data) {
+        Ok(fresh) if fresh.checksum == checksum && fresh.version_path == version_path => {
+            info!("cached manifest for {version_name} is up to date");
+        }
+        Ok(fresh) => {
+            info!("manifest for {version_name} changed, replacing cached copy");
+            let max_bytes = config.manifest_cache_max_bytes;
+            if let Err(err) = manifest_cache::store(&cache_dir, max_bytes, &fresh, is_current).await
+            {
+                warn!("failed to cache manifest for {version_name}: {err:#}");
+                manifest_cache::remove(&cache_dir, &version_name).await;
+            }
+            state.context_cache.remove(&key, "stale");
+        }
+        Err(err) => {
+            warn!("drop sent an unusable manifest for {version_name}: {err:?}");
+            manifest_cache::remove(&cache_dir, &version_name).await;
+            state.context_cache.remove(&key, "stale");
+        }
+    }
 }
 
-fn create_backend(
-    version_data: &VersionResponse,
-) -> Result<Box<dyn VersionBackend + Send + Sync>, StatusCode> {
-    let base_path = serde_json::from_str::<Value>(&version_data.source.options)
+fn persisted_from_response(
+    version_id: String,
+    version_data: VersionResponse,
+) -> Result<PersistedManifest, ErrorOption> {
+    let library_dir = library_base_dir(&version_data)?;
+    let version_path = version_path(&version_data, &library_dir)?;
+    let manifest = version_data
+        .manifest
+        .into_option()
+        .ok_or_else(|| anyhow!("drop sent version {version_id} without a manifest"))?;
+    let checksum = manifest_checksum(&manifest).map_err(anyhow::Error::from)?;
+
+    Ok(PersistedManifest {
+        version_id,
+        checksum,
+        library_dir,
+        version_path,
+        manifest: convert_protobuf_manifest(manifest),
+    })
+}
+
+fn build_context(
+    app_state: &AppState,
+    (game_id, version_name): ContextKey,
+    persisted: PersistedManifest,
+    epoch: u64,
+) -> Result<DownloadContext, StatusCode> {
+    let backend = create_backend(&persisted.version_path)?;
+    app_state.library_dirs.insert(persisted.library_dir);
+
+    Ok(DownloadContext {
+        game_id,
+        version_name,
+        manifest: Arc::new(persisted.manifest),
+        epoch,
+        backend,
+    })
+}
+
+fn library_base_dir(version_data: &VersionResponse) -> Result<PathBuf, StatusCode> {
+    let options = serde_json::from_str::<Value>(&version_data.source.options)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let base_path = base_path.get("baseDir").unwrap().as_str().unwrap();
+    let base_path = options
+        .get("baseDir")
+        .and_then(Value::as_str)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(PathBuf::from(base_path))
+}
 
-    let version_path = PathBuf::from(base_path);
-    let version_path = version_path.join(version_data.library_path.clone());
-    let version_path = match version_data.source.backend.unwrap() {
-        LibraryBackend::FILESYSTEM => version_path.join(version_data.version_path.clone()),
-        LibraryBackend::FLAT_FILESYSTEM => version_path,
-    };
+fn version_path(
+    version_data: &VersionResponse,
+    library_dir: &Path,
+) -> Result<PathBuf, anyhow::Error> {
+    let version_path = library_dir.join(version_data.library_path.clone());
+    match version_data.source.backend.enum_value() {
+        Ok(LibraryBackend::FILESYSTEM) => Ok(version_path.join(version_data.version_path.clone())),
+        Ok(LibraryBackend::FLAT_FILESYSTEM) => Ok(version_path),
+        Err(backend) => Err(anyhow!("drop sent an unknown library backend {backend}")),
+    }
+}
 
+fn create_backend(
+    version_path: &Path,
+) -> Result<Box<dyn VersionBackend + Send + Sync>, StatusCode> {
     if !version_path.exists() {
         warn!("{} path doesn't exist for version", version_path.display());
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
     let backend =
-        create_backend_constructor(&version_path).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        create_backend_constructor(version_path).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let backend = backend()
         .inspect_err(|err| warn!("failed to create version backend: {err:?}"))
@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    io::Error,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use aes::cipher::StreamCipher;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::Stream;
+use tokio::io::AsyncRead;
+use tokio_util::io::poll_read_buf;
+
+use crate::downloads::serve::Aes128Ctr64LE;
+
+/**
+Reads plaintext from each of `readers` in turn into a buffer of up to
+`capacity` bytes, encrypts it in place and sends it on as is. The buffer's
+allocation is taken back for the next read once the previous piece has been
+sent, so a response mostly reuses one allocation of a known size
+*/
+pub struct EncryptingStream<R> {
+    readers: VecDeque<R>,
+    cipher: Aes128Ctr64LE,
+    buffer: BytesMut,
+    capacity: usize,
+}
+
+impl<R> EncryptingStream<R> {
+    /**
+    `cipher` has to be seeked to where the first reader starts already
+    */
+    pub fn new(
+        readers: impl IntoIterator<Item = R>,
+        cipher: Aes128Ctr64LE,
+        capacity: usize,
+    ) -> Self {
+        Self {
+            readers: readers.into_iter().collect(),
+            cipher,
+            buffer: BytesMut::new(),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl<R> Stream for EncryptingStream<R>
+where
+    R: AsyncRead + Unpin,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.buffer.capacity() < this.capacity {
+            this.buffer.reserve(this.capacity);
+        }
+
+        while this.buffer.len() < this.capacity {
+            let Some(reader) = this.readers.front_mut() else {
+                break;
+            };
+            let remaining = this.capacity - this.buffer.len();
+            let mut spare = (&mut this.buffer).limit(remaining);
+            match poll_read_buf(Pin::new(reader), cx, &mut spare) {
+                Poll::Ready(Ok(0)) => {
+                    this.readers.pop_front();
+                }
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(err)) => {
+                    this.readers.clear();
+                    this.buffer.clear();
+                    return Poll::Ready(Some(Err(err)));
+                }
+                // Sends what's been read so far rather than waiting to fill up
+                Poll::Pending if this.buffer.is_empty() => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+
+        if this.buffer.is_empty() {
+            return Poll::Ready(None);
+        }
+        this.cipher.apply_keystream(&mut this.buffer);
+        Poll::Ready(Some(Ok(this.buffer.split().freeze())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::KeyIvInit;
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn cipher() -> Aes128Ctr64LE {
+        Aes128Ctr64LE::new(&[3; 16].into(), &[9; 16].into())
+    }
+
+    async fn pieces(readers: Vec<&'static [u8]>, capacity: usize) -> Vec<Bytes> {
+        EncryptingStream::new(readers, cipher(), capacity)
+            .map(Result::unwrap)
+            .collect()
+            .await
+    }
+
+    fn encrypted(plaintext: &[u8]) -> Vec<u8> {
+        let mut data = plaintext.to_vec();
+        cipher().apply_keystream(&mut data);
+        data
+    }
+
+    #[tokio::test]
+    async fn fills_pieces_across_readers() {
+        let readers: Vec<&[u8]> = vec![b"abcde", b"", b"fghijkl", b"mno"];
+        let pieces = pieces(readers, 4).await;
+
+        let lengths = pieces.iter().map(Bytes::len).collect::<Vec<_>>();
+        assert_eq!(lengths, [4, 4, 4, 3]);
+        assert_eq!(pieces.concat(), encrypted(b"abcdefghijklmno"));
+    }
+
+    #[tokio::test]
+    async fn ends_pieces_where_the_data_does() {
+        let exact = pieces(vec![b"abcdefgh"], 4).await;
+        assert_eq!(exact.iter().map(Bytes::len).collect::<Vec<_>>(), [4, 4]);
+
+        // A capacity of 0 is taken as 1
+        let single_bytes = pieces(vec![b"abc"], 0).await;
+        assert_eq!(
+            single_bytes.iter().map(Bytes::len).collect::<Vec<_>>(),
+            [1, 1, 1]
+        );
+        assert_eq!(single_bytes.concat(), encrypted(b"abc"));
+    }
+
+    #[tokio::test]
+    async fn ends_straight_away_without_data() {
+        assert!(pieces(Vec::new(), 4).await.is_empty());
+        assert!(pieces(vec![b"", b""], 4).await.is_empty());
+    }
+
+    struct Failing;
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<Result<(), Error>> {
+            Poll::Ready(Err(Error::other("disk on fire")))
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_after_an_error() {
+        let mut stream = EncryptingStream::new([Failing], cipher(), 4);
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -12,18 +12,25 @@ use axum::{
     response::IntoResponse,
 };
 use bytes::BufMut;
-use reqwest::{StatusCode, header::CONTENT_TYPE};
+use log::warn;
+use reqwest::{
+    StatusCode,
+    header::{CONTENT_TYPE, VARY},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::io::Write;
 use tokio::io::AsyncRead;
 use tokio_util::io::ReaderStream;
 
-use crate::{server::download::fetch_instance_games, state::AppState};
-
-pub async fn healthcheck() -> StatusCode {
-    StatusCode::OK
-}
+use crate::{
+    downloads::{
+        compression::Compression, invalidation::Invalidation, prewarm,
+        serve::ACCEPT_CHUNK_COMPRESSION, verify::start_scrub,
+    },
+    metrics::METRICS,
+    server::download::fetch_instance_games, state::AppState,
+};
 
 #[derive(Deserialize)]
 pub struct InvalidateBody {
@@ -35,19 +42,100 @@ pub async fn invalidate(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<InvalidateBody>,
 ) -> StatusCode {
-    state.context_cache.remove(&(payload.game, payload.version));
+    state
+        .context_cache
+        .invalidate(&Invalidation::Version(payload.game, payload.version))
+        .await;
     StatusCode::OK
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrewarmBody {
+    versions: Vec<VersionBody>,
+    #[serde(default)]
+    read_chunks: usize,
+}
+
+#[derive(Deserialize)]
+pub struct VersionBody {
+    game: String,
+    version: String,
+}
+
+/**
+Prewarms versions in the background, the same as a `PREWARM` from Drop.
+Only one runs at a time
+*/
+pub async fn prewarm(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PrewarmBody>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    prewarm::check_limits(payload.versions.len(), payload.read_chunks)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    let targets = payload
+        .versions
+        .into_iter()
+        .map(|version| (version.game, version.version))
+        .collect();
+    if prewarm::start_prewarm(&state, targets, payload.read_chunks) {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Ok(StatusCode::CONFLICT)
+    }
+}
+
+/**
+Checks every chunk of a version against its checksum in the background.
+Corrupt chunks are logged and reported to Drop
+*/
+pub async fn scrub(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<VersionBody>,
+) -> StatusCode {
+    if start_scrub(state, (payload.game, payload.version)) {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::CONFLICT
+    }
+}
+
+/**
+Re-reads the configuration, reporting what was applied and what needs
+a restart
+*/
+pub async fn reload(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = state
+        .config
+        .reload()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    Ok(Json(report))
+}
+
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, StatusCode> {
+    let body = METRICS.render(&state).map_err(|err| {
+        warn!("failed to render metrics: {err:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    Ok((headers, body))
+}
+
 struct SpeedtestStream {
     remaining: usize,
 }
 
 impl SpeedtestStream {
-    pub fn new() -> Self {
-        SpeedtestStream {
-            remaining: 1024 * 1024 * 50,
-        }
+    pub fn new(size: usize) -> Self {
+        SpeedtestStream { remaining: size }
     }
     fn content_length(&self) -> usize {
         self.remaining
@@ -63,7 +151,7 @@ impl AsyncRead for SpeedtestStream {
         if self.remaining > 0 {
             let mut writer = buf.writer();
 
-            let amount = writer.write(&ZERO);
+            let amount = writer.write(&ZERO[..self.remaining.min(ZERO.len())]);
             match amount {
                 Ok(amount) => self.remaining -= amount,
                 Err(err) => return Poll::Ready(Err(err)),
@@ -73,8 +161,8 @@ impl AsyncRead for SpeedtestStream {
     }
 }
 
-pub async fn speedtest() -> Result<impl IntoResponse, StatusCode> {
-    let speedtest = SpeedtestStream::new();
+pub async fn speedtest(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, StatusCode> {
+    let speedtest = SpeedtestStream::new(state.config.get().speedtest_size);
     let ct = speedtest.content_length();
     let speedtest_stream = ReaderStream::new(speedtest);
     let body = Body::from_stream(speedtest_stream);
@@ -98,25 +186,44 @@ struct Manifest {
     content: HashMap<String, Vec<GameData>>,
 }
 
-pub async fn manifest(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, StatusCode> {
+/**
+Lists every version, with the compression each would be sent with. That's
+`none` unless the client sends `X-Accept-Chunk-Compression` too, as
+clients that don't can't undo it
+*/
+pub async fn manifest(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
     let games = fetch_instance_games(&state).await?;
+    let config = state.config.get();
+    let accepted = headers
+        .get(ACCEPT_CHUNK_COMPRESSION)
+        .and_then(|accepted| accepted.to_str().ok());
 
     let mut content = HashMap::new();
     for game in games {
-        content.insert(
-            game.id,
-            game.versions
-                .into_iter()
-                .map(|v| GameData {
-                    version_id: v.version_id,
-                    compression: "none".to_owned(),
-                })
-                .collect::<Vec<GameData>>(),
-        );
+        let versions = game
+            .versions
+            .into_iter()
+            .map(|v| GameData {
+                // Clients still have to ask for it with each chunk
+                compression: accepted
+                    .and_then(|accepted| {
+                        let offered = config.compression.offered_for(&game.id, &v.version_id);
+                        Compression::negotiate(offered, accepted)
+                    })
+                    .map_or("none", Compression::name)
+                    .to_owned(),
+                version_id: v.version_id,
+            })
+            .collect::<Vec<GameData>>();
+        content.insert(game.id, versions);
     }
 
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(VARY, HeaderValue::from_static(ACCEPT_CHUNK_COMPRESSION));
 
     Ok((headers, json!(Manifest { content }).to_string()))
 }
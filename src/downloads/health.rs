@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, response::IntoResponse};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::{
+    downloads::{cache::CacheStats, chunk_cache::ChunkCacheStats, serve::file_permits},
+    droplet::manifest::manifests_in_flight,
+    state::AppState,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DropHealth {
+    connected: bool,
+    generation: u64,
+    protocol_version: u32,
+    rtt_ms: Option<u128>,
+    missed_heartbeats: u32,
+    seconds_since_last_message: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LibraryHealth {
+    path: String,
+    readable: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestCacheHealth {
+    path: String,
+    readable: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FilePermits {
+    available: usize,
+    total: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HealthReport {
+    ready: bool,
+    /// Ready, but only for versions in the manifest cache, as Drop isn't
+    /// connected
+    degraded: bool,
+    shutting_down: bool,
+    drop: DropHealth,
+    libraries: Vec<LibraryHealth>,
+    manifest_cache: ManifestCacheHealth,
+    context_cache: CacheStats,
+    /// Only there when it's enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_cache: Option<ChunkCacheStats>,
+    file_permits: FilePermits,
+    manifest_generations: usize,
+}
+
+async fn health_report(state: &AppState) -> HealthReport {
+    let connection = state.server.connection_state();
+
+    let paths = state
+        .library_dirs
+        .iter()
+        .map(|path| path.clone())
+        .collect::<Vec<_>>();
+    let mut libraries = Vec::with_capacity(paths.len());
+    for path in paths {
+        libraries.push(LibraryHealth {
+            readable: tokio::fs::read_dir(&path).await.is_ok(),
+            path: path.display().to_string(),
+        });
+    }
+
+    let manifest_cache_dir = state.config.get().manifest_cache_dir.clone();
+    let manifest_cache = ManifestCacheHealth {
+        readable: tokio::fs::read_dir(&manifest_cache_dir).await.is_ok(),
+        path: manifest_cache_dir.display().to_string(),
+    };
+
+    let (available, total) = file_permits();
+    let chunk_cache = state.context_cache.chunk_cache();
+    let shutting_down = state.server.is_shutting_down();
+
+    // Versions in the manifest cache can still be served without Drop
+    let ready = !shutting_down
+        && (connection.connected || manifest_cache.readable)
+        && libraries.iter().all(|library| library.readable);
+
+    HealthReport {
+        ready,
+        degraded: ready && !connection.connected,
+        shutting_down,
+        drop: DropHealth {
+            connected: connection.connected,
+            generation: connection.generation,
+            protocol_version: connection.peer.protocol_version,
+            rtt_ms: connection.rtt.map(|rtt| rtt.as_millis()),
+            missed_heartbeats: connection.missed_heartbeats,
+            seconds_since_last_message: state.server.since_last_message().as_secs(),
+        },
+        libraries,
+        manifest_cache,
+        context_cache: state.context_cache.stats(),
+        chunk_cache: chunk_cache.is_enabled().then(|| chunk_cache.stats()),
+        file_permits: FilePermits { available, total },
+        manifest_generations: manifests_in_flight(),
+    }
+}
+
+fn ready_status(ready: bool) -> StatusCode {
+    if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/**
+Full report on every component, with the same status code as `readiness`
+*/
+pub async fn healthcheck(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let report = health_report(&state).await;
+    (ready_status(report.ready), Json(report))
+}
+
+/**
+The process is up and answering requests
+*/
+pub async fn liveness() -> StatusCode {
+    StatusCode::OK
+}
+
+/**
+We can actually serve chunks: we aren't shutting down, Drop is connected
+or there's a manifest cache to serve from without it, and every library
+we've served from is still readable
+*/
+pub async fn readiness(State(state): State<Arc<AppState>>) -> StatusCode {
+    ready_status(health_report(&state).await.ready)
+}
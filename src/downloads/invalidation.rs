@@ -0,0 +1,205 @@
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+use log::{info, warn};
+use protobuf::Message;
+
+use crate::{
+    downloads::{cache::ContextKey, manifest_cache::is_safe},
+    proto::{
+        core::{DropBoundType, TorrentialBound},
+        version::{Invalidate, InvalidateComplete},
+    },
+    server::DropServer,
+};
+
+/**
+What a cache invalidation covers
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Version(String, String),
+    /// Every version of a game
+    Game(String),
+    All,
+}
+
+impl Invalidation {
+    #[must_use]
+    pub fn matches(&self, (game_id, version_id): &ContextKey) -> bool {
+        match self {
+            Invalidation::Version(game, version) => game == game_id && version == version_id,
+            Invalidation::Game(game) => game == game_id,
+            Invalidation::All => true,
+        }
+    }
+
+    /**
+    Reads the targets out of an `INVALIDATE` message
+    */
+    pub fn from_message(message: &Invalidate) -> Result<Vec<Self>, anyhow::Error> {
+        if message.all {
+            return Ok(vec![Invalidation::All]);
+        }
+
+        message
+            .targets
+            .iter()
+            .map(|target| match (&*target.game_id, &*target.version_id) {
+                ("", _) => Err(anyhow!("invalidation target without a game")),
+                (game_id, "") => Ok(Invalidation::Game(game_id.to_owned())),
+                (game_id, version_id) => Ok(Invalidation::Version(
+                    game_id.to_owned(),
+                    version_id.to_owned(),
+                )),
+            })
+            .collect()
+    }
+
+    /**
+    Removes what this covers from a directory laid out as
+    `<version id>/...`. Games aren't part of it, so invalidating one
+    removes everything
+    */
+    pub async fn remove_from(&self, dir: &Path) {
+        let paths = match self {
+            Invalidation::Version(_, version_id) => {
+                if !is_safe(version_id) {
+                    return;
+                }
+                vec![dir.join(version_id)]
+            }
+            Invalidation::Game(_) | Invalidation::All => {
+                let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+                    return;
+                };
+                let mut paths = Vec::new();
+                while let Ok(Some(entry)) = entries.next_entry().await {
+                    if entry.file_name().to_str().is_some_and(is_safe) {
+                        paths.push(entry.path());
+                    }
+                }
+                paths
+            }
+        };
+
+        for path in paths {
+            match tokio::fs::remove_dir_all(&path).await {
+                Ok(()) => info!("removed cached chunks in {}", path.display()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!("failed to remove {}: {err}", path.display()),
+            }
+        }
+    }
+}
+
+/**
+Counts the invalidations covering each key, so work that started before one
+can tell its results are stale before it publishes them. A key's epoch is
+the sum of the counts for everything, its game and its version, so it only
+ever goes up
+*/
+#[derive(Default)]
+pub struct Epochs {
+    all: AtomicU64,
+    games: DashMap<String, u64>,
+    versions: DashMap<ContextKey, u64>,
+}
+
+impl Epochs {
+    #[must_use]
+    pub fn get(&self, key: &ContextKey) -> u64 {
+        let game = self.games.get(&key.0).map_or(0, |count| *count);
+        let version = self.versions.get(key).map_or(0, |count| *count);
+        self.all.load(Ordering::SeqCst) + game + version
+    }
+
+    /**
+    Whether nothing covering `key` was invalidated since it was at `epoch`
+    */
+    #[must_use]
+    pub fn is_current(&self, key: &ContextKey, epoch: u64) -> bool {
+        self.get(key) == epoch
+    }
+
+    pub fn advance(&self, target: &Invalidation) {
+        match target {
+            Invalidation::Version(game_id, version_id) => {
+                *self
+                    .versions
+                    .entry((game_id.clone(), version_id.clone()))
+                    .or_default() += 1;
+            }
+            Invalidation::Game(game_id) => *self.games.entry(game_id.clone()).or_default() += 1,
+            Invalidation::All => {
+                self.all.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+pub async fn invalidate_rpc(
+    server: Arc<DropServer>,
+    message: TorrentialBound,
+) -> Result<(), anyhow::Error> {
+    let invalidate = Invalidate::parse_from_bytes(&message.data)?;
+    let targets = Invalidation::from_message(&invalidate)?;
+
+    let (mut contexts, mut manifests) = (0, 0);
+    for target in &targets {
+        let (target_contexts, target_manifests) = server.context_cache().invalidate(target).await;
+        contexts += target_contexts;
+        manifests += target_manifests;
+    }
+    info!("drop invalidated {targets:?}: dropped {contexts} contexts and {manifests} manifests");
+
+    let mut complete = InvalidateComplete::new();
+    complete.contexts = u32::try_from(contexts).unwrap_or(u32::MAX);
+    complete.manifests = u32::try_from(manifests).unwrap_or(u32::MAX);
+    server
+        .send_message(
+            DropBoundType::INVALIDATE_COMPLETE,
+            complete,
+            Some(message.message_id),
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(game_id: &str, version_id: &str) -> ContextKey {
+        (game_id.to_owned(), version_id.to_owned())
+    }
+
+    #[test]
+    fn epochs_advance_for_what_they_cover() {
+        let epochs = Epochs::default();
+        let v1 = epochs.get(&key("game", "v1"));
+        let v2 = epochs.get(&key("game", "v2"));
+        let other = epochs.get(&key("other", "v1"));
+
+        epochs.advance(&Invalidation::Version("game".to_owned(), "v1".to_owned()));
+        assert!(!epochs.is_current(&key("game", "v1"), v1));
+        assert!(epochs.is_current(&key("game", "v2"), v2));
+
+        epochs.advance(&Invalidation::Game("game".to_owned()));
+        assert!(!epochs.is_current(&key("game", "v2"), v2));
+        assert!(epochs.is_current(&key("other", "v1"), other));
+
+        let v1 = epochs.get(&key("game", "v1"));
+        epochs.advance(&Invalidation::All);
+        assert!(!epochs.is_current(&key("game", "v1"), v1));
+        assert!(!epochs.is_current(&key("other", "v1"), other));
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context as _;
+use droplet_rs::manifest::Manifest;
+use log::{info, warn};
+use protobuf::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{downloads::invalidation::Invalidation, proto::version::version_response};
+
+/**
+A converted manifest and where its files live, kept on disk so versions
+can be served straight after a restart, even before Drop is reachable.
+Stored as `<version id>.<checksum>.json` in `manifest_cache_dir`. Drop
+doesn't say which game a version belongs to, and the one in the request
+path is up to the client, so game IDs aren't part of it
+*/
+#[derive(Serialize, Deserialize)]
+pub struct PersistedManifest {
+    pub version_id: String,
+    /// `manifest_checksum` of the manifest Drop sent
+    pub checksum: String,
+    pub library_dir: PathBuf,
+    pub version_path: PathBuf,
+    pub manifest: Manifest,
+}
+
+/**
+Hashes a manifest as Drop sent it. Protobuf maps aren't encoded in a
+stable order, so chunks are hashed one by one, sorted by ID
+*/
+pub fn manifest_checksum(manifest: &version_response::Manifest) -> Result<String, protobuf::Error> {
+    let mut hasher = Sha256::new();
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+
+    field(manifest.version.as_bytes());
+    field(&manifest.size.to_le_bytes());
+    field(&manifest.key);
+
+    let mut chunks = manifest.chunks.iter().collect::<Vec<_>>();
+    chunks.sort_unstable_by_key(|(id, _)| *id);
+    for (id, chunk) in chunks {
+        field(id.as_bytes());
+        field(&chunk.write_to_bytes()?);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/**
+IDs come from request paths, so anything that could escape the cache
+directory isn't cached
+*/
+pub(crate) fn is_safe(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn cache_path(dir: &Path, version_id: &str, checksum: &str) -> Option<PathBuf> {
+    (is_safe(version_id) && is_safe(checksum))
+        .then(|| dir.join(format!("{version_id}.{checksum}.json")))
+}
+
+/**
+A manifest in the cache directory, as named by `cache_path`
+*/
+struct CachedFile {
+    path: PathBuf,
+    version_id: String,
+    checksum: String,
+    size: u64,
+    used: SystemTime,
+}
+
+/**
+Lists our files in the cache directory, leaving anything else alone in
+case it's shared
+*/
+async fn cached_files(dir: &Path) -> Vec<CachedFile> {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some((version_id, checksum)) = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|name| name.split_once('.'))
+            .filter(|(version_id, checksum)| is_safe(version_id) && is_safe(checksum))
+        else {
+            continue;
+        };
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        files.push(CachedFile {
+            path: entry.path(),
+            version_id: version_id.to_owned(),
+            checksum: checksum.to_owned(),
+            size: metadata.len(),
+            used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    files
+}
+
+/**
+Reads a version's manifest from the cache, if it's there and readable. Its
+modification time is bumped, as that's what eviction goes by
+*/
+pub async fn load(dir: &Path, version_id: &str) -> Option<PersistedManifest> {
+    if !is_safe(version_id) {
+        return None;
+    }
+    // Normally there's only one, but there can be more after a crash
+    let file = cached_files(dir)
+        .await
+        .into_iter()
+        .filter(|file| file.version_id == version_id)
+        .max_by_key(|file| file.used)?;
+
+    let contents = tokio::fs::read(&file.path).await.ok()?;
+    match serde_json::from_slice::<PersistedManifest>(&contents) {
+        Ok(persisted)
+            if persisted.version_id == version_id && persisted.checksum == file.checksum =>
+        {
+            touch(&file.path).await;
+            Some(persisted)
+        }
+        Ok(_) => {
+            warn!(
+                "{} doesn't match its name, ignoring it",
+                file.path.display()
+            );
+            None
+        }
+        Err(err) => {
+            warn!(
+                "ignoring unreadable cached manifest {}: {err}",
+                file.path.display()
+            );
+            None
+        }
+    }
+}
+
+async fn touch(path: &Path) {
+    let path = path.to_owned();
+    let touched = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .append(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())
+    })
+    .await;
+    if let Ok(Err(err)) = touched {
+        warn!("failed to mark cached manifest as used: {err}");
+    }
+}
+
+/**
+Writes a version's manifest to the cache, replacing any older copy, then
+evicts the least recently used manifests until the cache fits in
+`max_bytes`. It's written to a temporary file first, so a crash never
+leaves half of one. Nothing is kept unless `is_current` still holds once
+it's in place, so an invalidation in between can't be undone
+*/
+pub async fn store(
+    dir: &Path,
+    max_bytes: u64,
+    persisted: &PersistedManifest,
+    is_current: impl Fn() -> bool,
+) -> Result<(), anyhow::Error> {
+    let Some(path) = cache_path(dir, &persisted.version_id, &persisted.checksum) else {
+        return Ok(());
+    };
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("failed to create manifest cache {}", dir.display()))?;
+
+    let temporary = dir.join(format!(".{}.tmp", uuid::Uuid::new_v4().simple()));
+    tokio::fs::write(&temporary, serde_json::to_vec(persisted)?)
+        .await
+        .with_context(|| format!("failed to write {}", temporary.display()))?;
+    if !is_current() {
+        remove_file(&temporary).await;
+        return Ok(());
+    }
+    tokio::fs::rename(&temporary, &path)
+        .await
+        .with_context(|| format!("failed to move {} into place", path.display()))?;
+
+    // Invalidated while it was being moved, possibly after it was removed
+    if !is_current() {
+        remove_file(&path).await;
+        return Ok(());
+    }
+
+    let mut files = cached_files(dir).await;
+    for file in files.extract_if(.., |file| {
+        file.version_id == persisted.version_id && file.path != path
+    }) {
+        remove_file(&file.path).await;
+    }
+    evict(files, max_bytes).await;
+    Ok(())
+}
+
+/**
+Removes the least recently used of `files` until the rest fit in `max_bytes`
+*/
+async fn evict(mut files: Vec<CachedFile>, max_bytes: u64) {
+    let mut total = files.iter().map(|file| file.size).sum::<u64>();
+    files.sort_unstable_by_key(|file| file.used);
+    for file in files {
+        if total <= max_bytes {
+            break;
+        }
+        total -= file.size;
+        if remove_file(&file.path).await {
+            info!("evicted cached manifest for {}", file.version_id);
+        }
+    }
+}
+
+/**
+Removes a version's manifest from the cache, returning how many copies of
+it there were
+*/
+pub async fn remove(dir: &Path, version_id: &str) -> usize {
+    remove_matching(dir, |file| file.version_id == version_id).await
+}
+
+/**
+Removes every cached manifest `target` covers, returning how many there
+were. Which game a version belongs to isn't known, so invalidating a game
+removes every manifest, and the ones still needed are fetched again
+*/
+pub async fn invalidate(dir: &Path, target: &Invalidation) -> usize {
+    match target {
+        Invalidation::Version(_, version_id) => remove(dir, version_id).await,
+        Invalidation::Game(_) | Invalidation::All => remove_matching(dir, |_| true).await,
+    }
+}
+
+async fn remove_matching(dir: &Path, matches: impl Fn(&CachedFile) -> bool) -> usize {
+    let mut removed = 0;
+    for file in cached_files(dir).await {
+        if matches(&file) && remove_file(&file.path).await {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+async fn remove_file(path: &Path) -> bool {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => true,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
+        Err(err) => {
+            warn!("failed to remove cached manifest {}: {err}", path.display());
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::*;
+
+    fn persisted(version_id: &str, checksum: &str) -> PersistedManifest {
+        PersistedManifest {
+            version_id: version_id.to_owned(),
+            checksum: checksum.to_owned(),
+            library_dir: PathBuf::from("library"),
+            version_path: PathBuf::from("library/game/version"),
+            manifest: Manifest {
+                version: version_id.to_owned(),
+                chunks: HashMap::new(),
+                size: 0,
+                key: [0; 16],
+            },
+        }
+    }
+
+    async fn store_current(dir: &Path, max_bytes: u64, persisted: &PersistedManifest) {
+        store(dir, max_bytes, persisted, || true).await.unwrap();
+    }
+
+    async fn names(dir: &Path) -> Vec<String> {
+        let mut names = cached_files(dir)
+            .await
+            .into_iter()
+            .map(|file| format!("{}.{}", file.version_id, file.checksum))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn loads_what_was_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        store_current(dir.path(), u64::MAX, &persisted("v1", "aaaa")).await;
+
+        let loaded = load(dir.path(), "v1").await.unwrap();
+        assert_eq!(loaded.checksum, "aaaa");
+        assert_eq!(loaded.version_path, PathBuf::from("library/game/version"));
+        assert!(load(dir.path(), "v2").await.is_none());
+        assert!(load(dir.path(), "../v1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_new_checksum_replaces_the_old_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        store_current(dir.path(), u64::MAX, &persisted("v1", "aaaa")).await;
+        store_current(dir.path(), u64::MAX, &persisted("v1", "bbbb")).await;
+
+        assert_eq!(load(dir.path(), "v1").await.unwrap().checksum, "bbbb");
+        assert_eq!(names(dir.path()).await, ["v1.bbbb"]);
+    }
+
+    #[tokio::test]
+    async fn ignores_manifests_that_dont_match_their_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = serde_json::to_vec(&persisted("v1", "aaaa")).unwrap();
+        tokio::fs::write(dir.path().join("v1.bbbb.json"), contents)
+            .await
+            .unwrap();
+
+        assert!(load(dir.path(), "v1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_storing_once_invalidated() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path(), u64::MAX, &persisted("v1", "aaaa"), || false)
+            .await
+            .unwrap();
+
+        assert!(names(dir.path()).await.is_empty());
+        assert!(
+            tokio::fs::read_dir(dir.path())
+                .await
+                .unwrap()
+                .next_entry()
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_past_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        store_current(dir.path(), u64::MAX, &persisted("v1", "aaaa")).await;
+        let size = cached_files(dir.path()).await[0].size;
+
+        // Modification times can be coarse, so leave room between uses
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        store_current(dir.path(), u64::MAX, &persisted("v2", "aaaa")).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        load(dir.path(), "v1").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        store_current(dir.path(), size * 2, &persisted("v3", "aaaa")).await;
+
+        assert_eq!(names(dir.path()).await, ["v1.aaaa", "v3.aaaa"]);
+    }
+
+    #[tokio::test]
+    async fn invalidates_versions_by_id_and_games_as_a_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        for version_id in ["v1", "v2", "v3"] {
+            store_current(dir.path(), u64::MAX, &persisted(version_id, "aaaa")).await;
+        }
+        tokio::fs::write(dir.path().join("notes.txt"), "not ours")
+            .await
+            .unwrap();
+
+        let version = Invalidation::Version("any-game".to_owned(), "v1".to_owned());
+        assert_eq!(invalidate(dir.path(), &version).await, 1);
+        assert_eq!(names(dir.path()).await, ["v2.aaaa", "v3.aaaa"]);
+
+        let game = Invalidation::Game("any-game".to_owned());
+        assert_eq!(invalidate(dir.path(), &game).await, 2);
+        assert!(names(dir.path()).await.is_empty());
+        assert!(dir.path().join("notes.txt").exists());
+    }
+}
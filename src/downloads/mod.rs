@@ -1,3 +1,14 @@
 pub mod handlers;
 pub mod serve;
 pub mod download;
+pub mod cache;
+pub mod chunk_cache;
+pub mod compression;
+pub mod encrypt;
+pub mod manifest_cache;
+pub mod health;
+pub mod invalidation;
+pub mod prewarm;
+pub mod range;
+pub mod sendfile;
+pub mod verify;
@@ -0,0 +1,262 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::anyhow;
+use droplet_rs::manifest::ChunkData;
+use futures_util::{StreamExt, stream};
+use log::{info, warn};
+use protobuf::Message;
+use serde::Serialize;
+use tokio::{
+    io,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    DownloadContext,
+    downloads::{
+        cache::ContextKey,
+        serve::{file_permit, get_or_create_context},
+    },
+    proto::{
+        core::{DropBoundType, TorrentialBound},
+        version::{Prewarm, PrewarmComplete, prewarm},
+    },
+    server::DropServer,
+    state::AppState,
+};
+
+/**
+Most versions one prewarm can list
+*/
+pub const MAX_TARGETS: usize = 256;
+/**
+Most chunks one prewarm can read of each version
+*/
+pub const MAX_READ_CHUNKS: usize = 64;
+/**
+Versions prewarmed at once by each prewarm
+*/
+const CONCURRENCY: usize = 4;
+
+/**
+Set while a prewarm asked for over HTTP is running, as only one is at a time
+*/
+static PREWARMING: AtomicBool = AtomicBool::new(false);
+
+/**
+A `PREWARM` from Drop. Creating contexts needs the whole `AppState`, which
+the server doesn't have, so these are handed to `prewarm_subroutine`
+*/
+pub struct PrewarmRequest {
+    pub targets: Vec<ContextKey>,
+    pub read_chunks: usize,
+    pub done: oneshot::Sender<PrewarmReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrewarmReport {
+    pub prewarmed: usize,
+    pub failed: Vec<ContextKey>,
+}
+
+/**
+Errors if a prewarm asks for more than `MAX_TARGETS` versions, or more
+than `MAX_READ_CHUNKS` chunks of each
+*/
+pub fn check_limits(targets: usize, read_chunks: usize) -> Result<(), anyhow::Error> {
+    if targets > MAX_TARGETS {
+        return Err(anyhow!(
+            "prewarm of {targets} versions exceeds the limit of {MAX_TARGETS}"
+        ));
+    }
+    if read_chunks > MAX_READ_CHUNKS {
+        return Err(anyhow!(
+            "prewarm reading {read_chunks} chunks exceeds the limit of {MAX_READ_CHUNKS}"
+        ));
+    }
+    Ok(())
+}
+
+/**
+Starts prewarming in the background, returning false if a prewarm started
+this way is still running
+*/
+pub fn start_prewarm(state: &Arc<AppState>, targets: Vec<ContextKey>, read_chunks: usize) -> bool {
+    if PREWARMING.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    let app_state = state.clone();
+    state.server.spawn_tracked(async move {
+        tokio::select! {
+            _ = prewarm(&app_state, targets, read_chunks) => {}
+            () = app_state.server.shutting_down() => {}
+        }
+        PREWARMING.store(false, Ordering::Release);
+    });
+    true
+}
+
+/**
+Creates the contexts for `targets`, a few at a time, and reads the first
+`read_chunks` chunks of each so they're in the OS page cache
+*/
+pub async fn prewarm(
+    state: &Arc<AppState>,
+    targets: Vec<ContextKey>,
+    read_chunks: usize,
+) -> PrewarmReport {
+    let results = stream::iter(targets)
+        .map(|key| async move {
+            let result = prewarm_version(state, key.clone(), read_chunks).await;
+            (key, result)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = PrewarmReport::default();
+    for ((game_id, version_id), result) in results {
+        match result {
+            Ok(()) => report.prewarmed += 1,
+            Err(err) => {
+                warn!("failed to prewarm {game_id}/{version_id}: {err:#}");
+                report.failed.push((game_id, version_id));
+            }
+        }
+    }
+    info!(
+        "prewarmed {} versions, {} failed",
+        report.prewarmed,
+        report.failed.len()
+    );
+    report
+}
+
+async fn prewarm_version(
+    state: &Arc<AppState>,
+    (game_id, version_id): ContextKey,
+    read_chunks: usize,
+) -> Result<(), anyhow::Error> {
+    let context = get_or_create_context(state, game_id, version_id)
+        .await
+        .map_err(|status| anyhow!("failed to create context: {status}"))?;
+
+    for chunk in first_chunks(&context, read_chunks) {
+        read_chunk(&context, chunk).await?;
+    }
+    Ok(())
+}
+
+/**
+The chunks a client downloading the version file by file would ask for first
+*/
+fn first_chunks(context: &DownloadContext, count: usize) -> Vec<&ChunkData> {
+    let mut chunks = context
+        .manifest
+        .chunks
+        .values()
+        .filter(|chunk| !chunk.files.is_empty())
+        .collect::<Vec<_>>();
+    chunks.sort_unstable_by_key(|chunk| {
+        chunk
+            .files
+            .first()
+            .map(|file| (file.filename.as_str(), file.start))
+    });
+    chunks.truncate(count);
+    chunks
+}
+
+/**
+Reads a chunk's files and throws the data away
+*/
+async fn read_chunk(context: &DownloadContext, chunk: &ChunkData) -> Result<(), anyhow::Error> {
+    for file in &chunk.files {
+        let _permit = file_permit()
+            .await
+            .ok_or_else(|| anyhow!("file permits closed"))?;
+        let mut reader = context
+            .reader(
+                file.filename.clone(),
+                file.start as u64,
+                (file.start + file.length) as u64,
+            )
+            .await?;
+        io::copy(&mut reader, &mut io::sink()).await?;
+    }
+    Ok(())
+}
+
+/**
+Runs the prewarms Drop asks for over the link, until the server goes away
+*/
+pub async fn prewarm_subroutine(
+    state: Arc<AppState>,
+    mut requests: mpsc::UnboundedReceiver<PrewarmRequest>,
+) {
+    while let Some(request) = requests.recv().await {
+        let app_state = state.clone();
+        state.server.spawn_tracked(async move {
+            tokio::select! {
+                report = prewarm(&app_state, request.targets, request.read_chunks) => {
+                    // Drop's link may have gone while we were busy, which is fine
+                    let _ = request.done.send(report);
+                }
+                () = app_state.server.shutting_down() => {}
+            }
+        });
+    }
+}
+
+pub async fn prewarm_rpc(
+    server: Arc<DropServer>,
+    message: TorrentialBound,
+) -> Result<(), anyhow::Error> {
+    let request = Prewarm::parse_from_bytes(&message.data)?;
+    let targets = request
+        .targets
+        .into_iter()
+        .map(|target| {
+            if target.game_id.is_empty() || target.version_id.is_empty() {
+                Err(anyhow!("prewarm target without a game or version"))
+            } else {
+                Ok((target.game_id, target.version_id))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    check_limits(targets.len(), request.read_chunks as usize)?;
+
+    let (done, report) = oneshot::channel();
+    server.prewarm(PrewarmRequest {
+        targets,
+        read_chunks: request.read_chunks as usize,
+        done,
+    })?;
+    let report = report.await?;
+
+    let mut complete = PrewarmComplete::new();
+    complete.prewarmed = u32::try_from(report.prewarmed).unwrap_or(u32::MAX);
+    complete.failed = report
+        .failed
+        .into_iter()
+        .map(|(game_id, version_id)| {
+            let mut target = prewarm::Target::new();
+            target.game_id = game_id;
+            target.version_id = version_id;
+            target
+        })
+        .collect();
+    server
+        .send_message(
+            DropBoundType::PREWARM_COMPLETE,
+            complete,
+            Some(message.message_id),
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,410 @@
+use droplet_rs::manifest::FileEntry;
+
+/// Upper limit on the number of ranges served as multipart/byteranges,
+/// anything above this is served as a full response instead
+const MAX_RANGES: usize = 32;
+
+/**
+A satisfiable byte range within a chunk. `end` is exclusive
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ByteRange {
+    #[must_use]
+    pub fn full(length: usize) -> Self {
+        Self {
+            start: 0,
+            end: length,
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    #[must_use]
+    pub fn content_range(&self, total: usize) -> String {
+        format!(
+            "bytes {}-{}/{total}",
+            self.start,
+            self.end.saturating_sub(1)
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// The header can't be parsed, and should be ignored as per RFC 9110
+    Malformed,
+    /// None of the requested ranges overlap the chunk
+    Unsatisfiable,
+}
+
+/**
+A region of a single file that makes up part of a `ByteRange`.
+`start` and `end` are absolute offsets within the file
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileSegment<'a> {
+    pub filename: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+/**
+Parses a `Range` header value against a chunk of `total` bytes.
+Overlapping and adjacent ranges are coalesced, and the result is sorted
+*/
+pub fn parse_range_header(header: &str, total: usize) -> Result<Vec<ByteRange>, RangeError> {
+    let specs = header
+        .trim()
+        .strip_prefix("bytes=")
+        .ok_or(RangeError::Malformed)?;
+
+    let mut ranges = Vec::new();
+    let mut seen_spec = false;
+
+    for spec in specs.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+        seen_spec = true;
+        let (start, end) = spec.split_once('-').ok_or(RangeError::Malformed)?;
+        let start = start.trim();
+        let end = end.trim();
+
+        let range = if start.is_empty() {
+            let suffix = parse_offset(end)?;
+            ByteRange {
+                start: total.saturating_sub(suffix),
+                end: total,
+            }
+        } else {
+            let start = parse_offset(start)?;
+            let end = if end.is_empty() {
+                total
+            } else {
+                let end = parse_offset(end)?;
+                if end < start {
+                    return Err(RangeError::Malformed);
+                }
+                end.saturating_add(1).min(total)
+            };
+            if start >= total {
+                continue;
+            }
+            ByteRange { start, end }
+        };
+
+        if !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+
+    if !seen_spec {
+        return Err(RangeError::Malformed);
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+
+    ranges.sort_by_key(|v| v.start);
+    let mut coalesced: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+
+    if coalesced.len() > MAX_RANGES {
+        return Err(RangeError::Malformed);
+    }
+
+    Ok(coalesced)
+}
+
+fn parse_offset(value: &str) -> Result<usize, RangeError> {
+    if value.is_empty() || !value.bytes().all(|v| v.is_ascii_digit()) {
+        return Err(RangeError::Malformed);
+    }
+    // Anything too large to fit is past the end of the chunk anyway
+    Ok(value.parse::<usize>().unwrap_or(usize::MAX))
+}
+
+/**
+Maps a byte range within a chunk onto the regions of the chunk's files
+that need to be read to produce it
+*/
+#[must_use]
+pub fn file_segments(files: &[FileEntry], range: ByteRange) -> Vec<FileSegment<'_>> {
+    let mut segments = Vec::new();
+    let mut offset = 0;
+
+    for file in files {
+        let file_start = offset;
+        let file_end = offset + file.length;
+        offset = file_end;
+
+        let start = range.start.max(file_start);
+        let end = range.end.min(file_end);
+        if start < end {
+            segments.push(FileSegment {
+                filename: &file.filename,
+                start: file.start + (start - file_start),
+                end: file.start + (end - file_start),
+            });
+        }
+
+        if offset >= range.end {
+            break;
+        }
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+    use futures_util::TryStreamExt;
+
+    use super::*;
+    use crate::downloads::{encrypt::EncryptingStream, serve::Aes128Ctr64LE};
+
+    fn range(start: usize, end: usize) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn entry(filename: &str, start: usize, length: usize) -> FileEntry {
+        FileEntry {
+            filename: filename.to_owned(),
+            start,
+            length,
+            permissions: 0o644,
+        }
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(
+            parse_range_header("bytes=0-99", 1000),
+            Ok(vec![range(0, 100)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=900-", 1000),
+            Ok(vec![range(900, 1000)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=990-2000", 1000),
+            Ok(vec![range(990, 1000)])
+        );
+        assert_eq!(
+            parse_range_header(" bytes= 10 - 19 ", 1000),
+            Ok(vec![range(10, 20)])
+        );
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(
+            parse_range_header("bytes=-100", 1000),
+            Ok(vec![range(900, 1000)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=-5000", 1000),
+            Ok(vec![range(0, 1000)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=-0", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for header in [
+            "",
+            "bytes=",
+            "bytes=,",
+            "items=0-1",
+            "bytes=1",
+            "bytes=a-b",
+            "bytes=5-1",
+            "bytes=-",
+            "bytes=+1-2",
+        ] {
+            assert_eq!(
+                parse_range_header(header, 1000),
+                Err(RangeError::Malformed),
+                "{header:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(
+            parse_range_header("bytes=1000-", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range_header("bytes=2000-3000, 1500-", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range_header("bytes=99999999999999999999999-", 1000),
+            Err(RangeError::Unsatisfiable)
+        );
+        // Only the satisfiable ones are kept
+        assert_eq!(
+            parse_range_header("bytes=2000-3000, 0-0", 1000),
+            Ok(vec![range(0, 1)])
+        );
+    }
+
+    #[test]
+    fn coalesces_overlapping_and_adjacent_ranges() {
+        assert_eq!(
+            parse_range_header("bytes=500-599, 0-99, 50-149, 150-199, -100", 1000),
+            Ok(vec![range(0, 200), range(500, 600), range(900, 1000)])
+        );
+        assert_eq!(
+            parse_range_header("bytes=0-0, 0-0, 0-", 1000),
+            Ok(vec![range(0, 1000)])
+        );
+    }
+
+    #[test]
+    fn limits_the_number_of_ranges() {
+        let ranges = |count: usize| {
+            let specs = (0..count)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 4))
+                .collect::<Vec<_>>();
+            format!("bytes={}", specs.join(","))
+        };
+        assert_eq!(
+            parse_range_header(&ranges(MAX_RANGES), 1000).map(|v| v.len()),
+            Ok(MAX_RANGES)
+        );
+        assert_eq!(
+            parse_range_header(&ranges(MAX_RANGES + 1), 1000),
+            Err(RangeError::Malformed)
+        );
+        // Counted after coalescing
+        let overlapping = format!("bytes={}", vec!["0-9"; MAX_RANGES * 2].join(","));
+        assert_eq!(
+            parse_range_header(&overlapping, 1000),
+            Ok(vec![range(0, 10)])
+        );
+    }
+
+    #[test]
+    fn maps_ranges_onto_files() {
+        let files = [entry("a", 100, 10), entry("b", 0, 5), entry("c", 20, 10)];
+
+        assert_eq!(
+            file_segments(&files, range(0, 25)),
+            vec![
+                FileSegment {
+                    filename: "a",
+                    start: 100,
+                    end: 110
+                },
+                FileSegment {
+                    filename: "b",
+                    start: 0,
+                    end: 5
+                },
+                FileSegment {
+                    filename: "c",
+                    start: 20,
+                    end: 30
+                },
+            ]
+        );
+        assert_eq!(
+            file_segments(&files, range(8, 16)),
+            vec![
+                FileSegment {
+                    filename: "a",
+                    start: 108,
+                    end: 110
+                },
+                FileSegment {
+                    filename: "b",
+                    start: 0,
+                    end: 5
+                },
+                FileSegment {
+                    filename: "c",
+                    start: 20,
+                    end: 21
+                },
+            ]
+        );
+        assert_eq!(
+            file_segments(&files, range(10, 15)),
+            vec![FileSegment {
+                filename: "b",
+                start: 0,
+                end: 5
+            }]
+        );
+        assert_eq!(file_segments(&files, range(3, 3)), vec![]);
+    }
+
+    /**
+    Encrypting a range on its own, from the files it maps onto, has to give
+    the same bytes as that slice of the whole chunk encrypted at once
+    */
+    #[tokio::test]
+    async fn ranges_match_a_full_download() {
+        let key = [3; 16];
+        let iv = [9; 16];
+        let contents = [
+            ("a", (0..200u8).collect::<Vec<_>>()),
+            ("b", (0..37u8).rev().collect::<Vec<_>>()),
+            ("c", vec![0x5a; 90]),
+        ];
+        let files = [entry("a", 13, 150), entry("b", 0, 37), entry("c", 1, 80)];
+        let read = |segment: &FileSegment| {
+            let (_, data) = contents
+                .iter()
+                .find(|(name, _)| *name == segment.filename)
+                .unwrap();
+            &data[segment.start..segment.end]
+        };
+
+        let total = files.iter().map(|v| v.length).sum();
+        let mut full = file_segments(&files, ByteRange::full(total))
+            .iter()
+            .flat_map(|segment| read(segment).to_vec())
+            .collect::<Vec<_>>();
+        Aes128Ctr64LE::new(&key.into(), &iv.into()).apply_keystream(&mut full);
+
+        for (start, end) in [(0, total), (0, 1), (15, 17), (16, 32), (140, 200), (1, 266)] {
+            let range = range(start, end);
+            let readers = file_segments(&files, range)
+                .iter()
+                .map(&read)
+                .collect::<Vec<_>>();
+            let mut cipher = Aes128Ctr64LE::new(&key.into(), &iv.into());
+            cipher.seek(start as u64);
+
+            let encrypted = EncryptingStream::new(readers, cipher, 7)
+                .map_ok(|piece| piece.to_vec())
+                .try_concat()
+                .await
+                .unwrap();
+            assert_eq!(&encrypted[..], &full[start..end], "{start}-{end}");
+        }
+    }
+}
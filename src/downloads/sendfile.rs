@@ -0,0 +1,526 @@
+use std::{
+    fmt::Write as _,
+    io::{Error, ErrorKind, IoSlice},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    Router,
+    http::header::{CONTENT_LENGTH, EXPECT, IF_RANGE, RANGE, TRANSFER_ENCODING, UPGRADE},
+};
+use bytes::{Buf, Bytes, BytesMut};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use log::{debug, warn};
+use prometheus::IntCounter;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    downloads::{
+        chunk_cache::ChunkKey,
+        manifest_cache::is_safe,
+        serve::{ACCEPT_CHUNK_COMPRESSION, etag, file_permit, get_or_create_context},
+    },
+    metrics::METRICS,
+    state::AppState,
+};
+
+/**
+Longest request head looked at. Anything longer goes to hyper, which
+answers it properly
+*/
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_HEADERS: usize = 32;
+const CHUNK_PATH: &str = "/api/v1/depot/content/";
+
+/**
+Serves `app` on `listener` until `stopping` is cancelled, then waits for
+the connections that are open to finish. Whole chunks in the chunk cache
+are written straight from their file to the socket, see
+`serve_connection`
+*/
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    state: Arc<AppState>,
+    stopping: CancellationToken,
+) {
+    let connections = TaskTracker::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) if is_connection_error(&err) => continue,
+                Err(err) => {
+                    // Usually out of file descriptors, which frees up as connections close
+                    warn!("failed to accept connection: {err}");
+                    sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            () = stopping.cancelled() => break,
+        };
+        connections.spawn(serve_connection(
+            stream,
+            app.clone(),
+            state.clone(),
+            stopping.clone(),
+        ));
+    }
+    connections.close();
+    connections.wait().await;
+}
+
+fn is_connection_error(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+/**
+Answers requests on one connection. As long as they're plain `GET`s for
+whole chunks that are in the chunk cache, the headers are written here and
+the file is handed to the kernel with `sendfile`, skipping hyper and every
+copy through userspace. The first request that's anything else hands the
+connection, and what was read of it, to hyper for good
+*/
+async fn serve_connection(
+    mut stream: TcpStream,
+    app: Router,
+    state: Arc<AppState>,
+    stopping: CancellationToken,
+) {
+    let read = if state.context_cache.chunk_cache().is_enabled() {
+        match serve_cached(&mut stream, &state, &stopping).await {
+            Some(read) => read,
+            None => return,
+        }
+    } else {
+        Bytes::new()
+    };
+
+    let service = TowerToHyperService::new(app);
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(
+        TokioIo::new(Prefixed {
+            prefix: read,
+            stream,
+        }),
+        service,
+    );
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        () = stopping.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!("connection failed: {err}");
+    }
+}
+
+/**
+Sends cached chunks until a request comes that can't be answered that
+way, returning what was read of it. `None` if the connection is done
+*/
+async fn serve_cached(
+    stream: &mut TcpStream,
+    state: &Arc<AppState>,
+    stopping: &CancellationToken,
+) -> Option<Bytes> {
+    let mut buffer = BytesMut::new();
+    loop {
+        let (request, length) = match parse(&buffer) {
+            Parsed::Chunk(request, length) => (request, length),
+            Parsed::Partial if buffer.len() < MAX_HEAD => {
+                tokio::select! {
+                    read = stream.read_buf(&mut buffer) => match read {
+                        Ok(0) | Err(_) => return None,
+                        Ok(_) => continue,
+                    },
+                    () = stopping.cancelled() => return None,
+                }
+            }
+            Parsed::Partial | Parsed::Other => return Some(buffer.freeze()),
+        };
+
+        let started = Instant::now();
+        match send_cached(stream, state, &request).await {
+            Ok(true) => {
+                METRICS
+                    .chunk_requests
+                    .with_label_values(&["200"])
+                    .observe(started.elapsed().as_secs_f64());
+            }
+            Ok(false) => return Some(buffer.freeze()),
+            Err(err) => {
+                // Part of the response might be out, so nothing else can follow it
+                debug!("failed to send cached chunk {}: {err}", request.chunk_id);
+                return None;
+            }
+        }
+        buffer.advance(length);
+        if request.close || stopping.is_cancelled() {
+            return None;
+        }
+    }
+}
+
+struct ChunkRequest {
+    game_id: String,
+    version_id: String,
+    chunk_id: String,
+    /// The client asked for the connection to be closed after this
+    close: bool,
+}
+
+enum Parsed {
+    Partial,
+    /// A request that might be for a cached chunk, and the length of its head
+    Chunk(ChunkRequest, usize),
+    Other,
+}
+
+/**
+Reads a request head, picking out `GET`s for whole chunks. Anything that
+needs more than the chunk, like ranges, compression, a body or an upgrade,
+is left to hyper
+*/
+fn parse(buffer: &[u8]) -> Parsed {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let length = match request.parse(buffer) {
+        Ok(httparse::Status::Complete(length)) => length,
+        Ok(httparse::Status::Partial) => return Parsed::Partial,
+        Err(_) => return Parsed::Other,
+    };
+    if request.method != Some("GET") || request.version != Some(1) {
+        return Parsed::Other;
+    }
+    let Some([game_id, version_id, chunk_id]) = request.path.and_then(chunk_path) else {
+        return Parsed::Other;
+    };
+
+    let handled_elsewhere = [
+        RANGE.as_str(),
+        IF_RANGE.as_str(),
+        CONTENT_LENGTH.as_str(),
+        TRANSFER_ENCODING.as_str(),
+        EXPECT.as_str(),
+        UPGRADE.as_str(),
+        ACCEPT_CHUNK_COMPRESSION,
+    ];
+    let mut close = false;
+    for header in request.headers.iter() {
+        if handled_elsewhere
+            .iter()
+            .any(|name| header.name.eq_ignore_ascii_case(name))
+        {
+            return Parsed::Other;
+        }
+        if header.name.eq_ignore_ascii_case("connection") {
+            let Ok(value) = std::str::from_utf8(header.value) else {
+                return Parsed::Other;
+            };
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    close = true;
+                } else if !option.eq_ignore_ascii_case("keep-alive") {
+                    return Parsed::Other;
+                }
+            }
+        }
+    }
+
+    Parsed::Chunk(
+        ChunkRequest {
+            game_id: game_id.to_owned(),
+            version_id: version_id.to_owned(),
+            chunk_id: chunk_id.to_owned(),
+            close,
+        },
+        length,
+    )
+}
+
+/**
+The IDs in a chunk's path. Ones that need decoding, or come with a query,
+aren't picked out
+*/
+fn chunk_path(path: &str) -> Option<[&str; 3]> {
+    let ids = path
+        .strip_prefix(CHUNK_PATH)?
+        .split('/')
+        .collect::<Vec<_>>();
+    let ids: [&str; 3] = ids.try_into().ok()?;
+    ids.iter().all(|id| is_safe(id)).then_some(ids)
+}
+
+/**
+Writes the response for a whole cached chunk, returning false, having
+written nothing, if it isn't cached as is
+*/
+async fn send_cached(
+    stream: &mut TcpStream,
+    state: &Arc<AppState>,
+    request: &ChunkRequest,
+) -> Result<bool, Error> {
+    let cache = state.context_cache.chunk_cache();
+    let Ok(context) =
+        get_or_create_context(state, request.game_id.clone(), request.version_id.clone()).await
+    else {
+        return Ok(false);
+    };
+    let Some(chunk) = context.manifest.chunks.get(&request.chunk_id) else {
+        return Ok(false);
+    };
+    let key = ChunkKey::new(&context, &request.chunk_id, chunk, None);
+    if !cache.contains(&key) {
+        return Ok(false);
+    }
+    let Some((file, size)) = cache.open(&key).await else {
+        return Ok(false);
+    };
+    let total_length: usize = chunk.files.iter().map(|file| file.length).sum();
+    if size != total_length as u64 {
+        return Ok(false);
+    }
+    let Some(_permit) = file_permit().await else {
+        return Ok(false);
+    };
+
+    let config = state.config.get();
+    let mut head = format!(
+        "HTTP/1.1 200 OK\r\n\
+        content-type: application/octet-stream\r\n\
+        accept-ranges: bytes\r\n\
+        content-length: {size}\r\n\
+        date: {}\r\n",
+        httpdate::fmt_http_date(SystemTime::now())
+    );
+    if let Some(etag) = etag(chunk).as_ref().and_then(|etag| etag.to_str().ok()) {
+        let _ = write!(head, "etag: {etag}\r\n");
+    }
+    let offered = config
+        .compression
+        .offered_for(&context.game_id, &context.version_name);
+    if !offered.is_empty() {
+        let _ = write!(head, "vary: {ACCEPT_CHUNK_COMPRESSION}\r\n");
+    }
+    if request.close {
+        head.push_str("connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+
+    let bytes_served = METRICS
+        .bytes_served
+        .with_label_values(&[&context.version_name]);
+    send_file(stream, file, size, config.read_buffer_size, &bytes_served).await?;
+    Ok(true)
+}
+
+/**
+Sends the first `length` bytes of `file` with `sendfile`, at most
+`batch_size` at a time, so a read from disk doesn't hold up the thread for
+long
+*/
+#[cfg(target_os = "linux")]
+pub async fn send_file(
+    stream: &TcpStream,
+    file: File,
+    length: u64,
+    batch_size: usize,
+    bytes_served: &IntCounter,
+) -> Result<(), Error> {
+    let file = file.into_std().await;
+    let mut offset = 0;
+    while offset < length {
+        let count =
+            usize::try_from(length - offset).map_or(batch_size, |left| left.min(batch_size));
+        let sent = stream
+            .async_io(tokio::io::Interest::WRITABLE, || {
+                rustix::fs::sendfile(stream, &file, Some(&mut offset), count).map_err(Error::from)
+            })
+            .await?;
+        if sent == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "file is shorter than expected",
+            ));
+        }
+        bytes_served.inc_by(sent as u64);
+    }
+    Ok(())
+}
+
+/**
+Copies the first `length` bytes of `file`, where there's no `sendfile`
+*/
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file(
+    stream: &mut TcpStream,
+    file: File,
+    length: u64,
+    _batch_size: usize,
+    bytes_served: &IntCounter,
+) -> Result<(), Error> {
+    let copied = tokio::io::copy(&mut file.take(length), stream).await?;
+    bytes_served.inc_by(copied);
+    if copied < length {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "file is shorter than expected",
+        ));
+    }
+    Ok(())
+}
+
+/**
+A connection with what was already read from it put back in front
+*/
+struct Prefixed {
+    prefix: Bytes,
+    stream: TcpStream,
+}
+
+impl AsyncRead for Prefixed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.stream).poll_read(cx, buf);
+        }
+        let length = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix.split_to(length));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Prefixed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn chunk(parsed: Parsed) -> Option<(ChunkRequest, usize)> {
+        match parsed {
+            Parsed::Chunk(request, length) => Some((request, length)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn picks_out_plain_chunk_requests() {
+        let head = b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nHost: depot\r\n\r\nGET /next";
+        let (request, length) = chunk(parse(head)).unwrap();
+        assert_eq!(
+            [request.game_id, request.version_id, request.chunk_id],
+            ["game", "v1", "c1"]
+        );
+        assert!(!request.close);
+        assert_eq!(&head[length..], b"GET /next");
+
+        let closing = b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(chunk(parse(closing)).unwrap().0.close);
+    }
+
+    #[test]
+    fn leaves_everything_else_to_hyper() {
+        let heads: [&[u8]; 9] = [
+            b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nRange: bytes=0-1\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nX-Accept-Chunk-Compression: zstd\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nConnection: upgrade\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.0\r\n\r\n",
+            b"HEAD /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c%31 HTTP/1.1\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c1?x=1 HTTP/1.1\r\n\r\n",
+            b"GET /api/v1/depot/content/game/v1/c1/ HTTP/1.1\r\n\r\n",
+            b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+        ];
+        for head in heads {
+            assert!(
+                matches!(parse(head), Parsed::Other),
+                "{}",
+                String::from_utf8_lossy(head)
+            );
+        }
+        assert!(matches!(
+            parse(b"GET /api/v1/depot/content/game/v1/c1 HTTP/1.1\r\nHost"),
+            Parsed::Partial
+        ));
+    }
+
+    #[tokio::test]
+    async fn sends_files_whole() {
+        let contents = (0..=u8::MAX).cycle().take(300_000).collect::<Vec<_>>();
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&contents).unwrap();
+        file.write_all(b"not sent").unwrap();
+        let file = File::from_std(file);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let sending = tokio::spawn(async move {
+            let counter = IntCounter::new("sent", "bytes sent").unwrap();
+            send_file(&server, file, 300_000, 64 * 1024, &counter).await?;
+            Ok::<_, Error>(counter.get())
+        });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(sending.await.unwrap().unwrap(), 300_000);
+        assert_eq!(received, contents);
+    }
+}
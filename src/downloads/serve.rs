@@ -1,34 +1,70 @@
 use std::{
-    io::Error,
+    io::{Error, SeekFrom},
     sync::{Arc, LazyLock},
+    time::Instant,
 };
 
-use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::cipher::{KeyIvInit, StreamCipherSeek};
 use axum::{
     body::Body,
     extract::{Path, State},
-    http::HeaderMap,
-    response::IntoResponse,
+    http::{
+        HeaderMap, HeaderValue,
+        header::{
+            ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, RANGE, VARY,
+        },
+    },
+    response::{IntoResponse, Response},
 };
 use bytes::Bytes;
-use dashmap::{DashMap, mapref::one::RefMut};
-use droplet_rs::{
-    manifest::ChunkData,
-    versions::types::{MinimumFileObject, VersionFile},
+use droplet_rs::{manifest::ChunkData, versions::types::MinimumFileObject};
+use futures_util::{
+    Stream, StreamExt,
+    future::ready,
+    stream::{self, BoxStream},
 };
-use futures_util::{Stream, StreamExt, stream};
-use log::{error, info};
+use log::{error, info, warn};
 use pin_project_lite::pin_project;
 use reqwest::StatusCode;
-use tokio::sync::{Semaphore, SemaphorePermit};
-use tokio_util::io::ReaderStream;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{Semaphore, SemaphorePermit},
+};
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use crate::{
-    DownloadContext, GLOBAL_CONTEXT_SEMAPHORE, downloads::download::create_download_context,
+    DownloadContext,
+    downloads::{
+        chunk_cache::{self, ChunkKey},
+        compression::{Compression, Encoding},
+        download::create_download_context,
+        encrypt::EncryptingStream,
+        range::{ByteRange, RangeError, file_segments, parse_range_header},
+        verify::{ChunkCheck, VerifyingStream},
+    },
+    metrics::METRICS,
     state::AppState,
 };
 
-type Aes128Ctr64LE = ctr::Ctr64LE<aes::Aes128>;
+pub type Aes128Ctr64LE = ctr::Ctr64LE<aes::Aes128>;
+type ByteStream = BoxStream<'static, Result<Bytes, Error>>;
+
+/**
+Names the compression a chunk was sent with. Not `Content-Encoding`, as
+the compression is under the encryption, where HTTP clients can't undo it
+*/
+pub const CHUNK_COMPRESSION: &str = "x-chunk-compression";
+/**
+The compressions a client can undo, in the syntax of `Accept-Encoding`.
+Not `Accept-Encoding` itself, as HTTP clients send that on their own for
+compression they undo before the body reaches the caller
+*/
+pub const ACCEPT_CHUNK_COMPRESSION: &str = "x-accept-chunk-compression";
+/**
+The IV a compressed chunk was encrypted with, in hex. See `Encoding`
+*/
+pub const CHUNK_IV: &str = "x-chunk-iv";
 
 pin_project! {
     struct SemaphoreStream<'a, T>
@@ -68,130 +104,511 @@ static SEMPAHORE_COUNT: LazyLock<usize> =
     LazyLock::new(|| file_open_limit::get().expect("failed to count max open files"));
 static FILE_SEMAPHORE: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(*SEMPAHORE_COUNT));
 
+/**
+Open file permits that are free right now, out of the total
+*/
+#[must_use]
+pub fn file_permits() -> (usize, usize) {
+    (FILE_SEMAPHORE.available_permits(), *SEMPAHORE_COUNT)
+}
+
+/**
+Waits for a single open file permit, for reads outside of requests
+*/
+pub(crate) async fn file_permit() -> Option<SemaphorePermit<'static>> {
+    FILE_SEMAPHORE.acquire().await.ok()
+}
+
 pub async fn serve_file(
+    state: State<Arc<AppState>>,
+    path: Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let started = Instant::now();
+    let result = serve_chunk(state, path, headers).await;
+
+    let status = result
+        .as_ref()
+        .map_or_else(|status| *status, Response::status);
+    METRICS
+        .chunk_requests
+        .with_label_values(&[status.as_str()])
+        .observe(started.elapsed().as_secs_f64());
+
+    result
+}
+
+async fn serve_chunk(
     State(state): State<Arc<AppState>>,
     Path((game_id, version_name, chunk_id)): Path<(String, String, String)>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let context_cache = &state.context_cache;
-
-    let mut context = get_or_create_context(&state, context_cache, game_id, version_name).await?;
-    context.reset_last_access();
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let context = get_or_create_context(&state, game_id, version_name).await?;
 
     let chunk_data = lookup_chunk(&chunk_id, &context)?;
-    if chunk_data.files.len() >= *SEMPAHORE_COUNT {
-        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    let total_length: usize = chunk_data.files.iter().map(|v| v.length).sum();
+
+    let Ok(ranges) = requested_ranges(&headers, chunk_data, total_length) else {
+        return unsatisfiable(total_length);
+    };
+
+    let (offered, compression) = negotiate_compression(&state, &context, &headers);
+    // Ranges are of the uncompressed chunk
+    let encoding = compression
+        .filter(|_| ranges.is_none())
+        .map(|compression| Encoding {
+            compression,
+            level: state.config.get().compression.level,
+        });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.extend(etag(chunk_data).map(|etag| (ETAG, etag)));
+    if !offered.is_empty() {
+        headers.insert(VARY, HeaderValue::from_static(ACCEPT_CHUNK_COMPRESSION));
     }
-    let permit = FILE_SEMAPHORE
-        .acquire_many(chunk_data.files.len().try_into().unwrap())
-        .await
-        .map_err(|_| StatusCode::INSUFFICIENT_STORAGE)?;
-    let mut streams = Vec::with_capacity(chunk_data.files.len());
-    let mut content_length = 0;
 
-    for file_entry in &chunk_data.files {
-        let reader = get_file_reader(
-            &mut context,
-            file_entry.filename.clone(),
-            file_entry.start,
-            file_entry.start + file_entry.length,
+    // Compressed chunks are only sent once they're cached
+    for candidate in encoding.map(Some).into_iter().chain([None]) {
+        if let Some(response) = cached_response(
+            &state,
+            &context,
+            (&chunk_id, chunk_data),
+            ranges.as_deref(),
+            candidate,
+            headers.clone(),
         )
-        .await?;
-
-        let stream = ReaderStream::new(reader);
-        streams.push(stream);
-        content_length += file_entry.length;
+        .await?
+        {
+            return Ok(response);
+        }
+        // Only what the client negotiated is cached, and only whole chunks
+        if candidate == encoding && ranges.is_none() {
+            chunk_cache::fill(&state, context.clone(), chunk_id.clone(), candidate);
+        }
     }
 
-    let stream = stream::iter(streams).flatten();
-    let mut cipher = Aes128Ctr64LE::new(&context.manifest.key.into(), &chunk_data.iv.into());
-    let encrypted_stream = stream.chunks(16).map(move |raw| -> Result<Bytes, Error> {
-        let data: Result<Vec<Bytes>, Error> = raw.into_iter().collect();
-        let mut data = data?.concat();
+    let full = ByteRange::full(total_length);
+    let requested = ranges.clone().unwrap_or_else(|| vec![full]);
+    let permit = acquire_file_permits(chunk_data, &requested).await?;
+    let config = state.config.get();
+    let buffer_size = config.read_buffer_size;
+    // Multipart responses replace this
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
 
-        cipher.apply_keystream(&mut data);
+    let (status, body) = match ranges.as_deref() {
+        None => {
+            let check = config.verify_chunks.then(|| {
+                ChunkCheck::new(state.server.clone(), &context, chunk_id.clone(), chunk_data)
+            });
+            let stream = range_stream(&context, chunk_data, full, check, buffer_size).await?;
+            headers.insert(CONTENT_LENGTH, total_length.into());
+            (
+                StatusCode::OK,
+                Body::from_stream(SemaphoreStream::new(stream, permit)),
+            )
+        }
+        Some([range]) => {
+            let stream = range_stream(&context, chunk_data, *range, None, buffer_size).await?;
+            headers.insert(CONTENT_LENGTH, range.len().into());
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&range.content_range(total_length))
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Body::from_stream(SemaphoreStream::new(stream, permit)),
+            )
+        }
+        Some(ranges) => {
+            let boundary = uuid::Uuid::new_v4().simple().to_string();
+            let (stream, content_length) = multipart_stream(
+                &context,
+                chunk_data,
+                ranges,
+                total_length,
+                &boundary,
+                buffer_size,
+            )
+            .await?;
+            headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+            headers.insert(CONTENT_LENGTH, content_length.into());
+            (
+                StatusCode::PARTIAL_CONTENT,
+                Body::from_stream(SemaphoreStream::new(stream, permit)),
+            )
+        }
+    };
 
-        Ok(data.into())
-    });
-    let permit_stream = SemaphoreStream::new(encrypted_stream, permit);
-    let body: Body = Body::from_stream(permit_stream);
+    Ok((status, headers, body).into_response())
+}
+/**
+The ranges asked for, if any. Malformed `Range` headers are ignored, as are
+ranges with an `If-Range` that doesn't match the chunk's `ETag`
+*/
+fn requested_ranges(
+    headers: &HeaderMap,
+    chunk_data: &ChunkData,
+    total_length: usize,
+) -> Result<Option<Vec<ByteRange>>, RangeError> {
+    // Dates never match, there's no Last-Modified to compare them with
+    if let Some(validator) = headers.get(IF_RANGE)
+        && Some(validator) != etag(chunk_data).as_ref()
+    {
+        return Ok(None);
+    }
+    match headers.get(RANGE).map(HeaderValue::to_str) {
+        Some(Ok(header)) => match parse_range_header(header, total_length) {
+            Ok(ranges) => Ok(Some(ranges)),
+            Err(RangeError::Malformed) => Ok(None),
+            Err(err) => Err(err),
+        },
+        _ => Ok(None),
+    }
+}
+/**
+The `ETag` is built from the chunk's checksum in the manifest, which is a
+strong validator for its plaintext. The encryption is the same every time
+too, as the key and IV come from the manifest
+*/
+pub(crate) fn etag(chunk_data: &ChunkData) -> Option<HeaderValue> {
+    HeaderValue::from_str(&format!("\"{}\"", chunk_data.checksum)).ok()
+}
 
+fn unsatisfiable(total_length: usize) -> Result<Response, StatusCode> {
     let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
-    headers.insert("Content-Length", content_length.into());
+    headers.insert(
+        CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes */{total_length}"))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    );
+    Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
+}
+/**
+Serves a full chunk, or a single range of it, straight from the chunk
+cache, compressed with `encoding` if there is one. On a miss, returns
+`None`, so it's assembled as usual
+*/
+async fn cached_response(
+    state: &Arc<AppState>,
+    context: &Arc<DownloadContext>,
+    (chunk_id, chunk_data): (&str, &ChunkData),
+    ranges: Option<&[ByteRange]>,
+    encoding: Option<Encoding>,
+    mut headers: HeaderMap,
+) -> Result<Option<Response>, StatusCode> {
+    let cache = state.context_cache.chunk_cache();
+    let requested = match ranges {
+        None => None,
+        Some([range]) => Some(*range),
+        Some(_) => return Ok(None),
+    };
+    if !cache.is_enabled() {
+        return Ok(None);
+    }
 
-    Ok((headers, body))
+    let key = ChunkKey::new(context, chunk_id, chunk_data, encoding);
+    let Some((file, size)) = cache.open(&key).await else {
+        return Ok(None);
+    };
+    let total_length: usize = match encoding {
+        // Whatever size it compressed to
+        Some(_) => size
+            .try_into()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => chunk_data.files.iter().map(|v| v.length).sum(),
+    };
+    if size != total_length as u64 {
+        warn!("cached chunk {chunk_id} is {size} bytes rather than {total_length}, dropping it");
+        cache.remove(&key).await;
+        return Ok(None);
+    }
+
+    let permit = file_permit()
+        .await
+        .ok_or(StatusCode::INSUFFICIENT_STORAGE)?;
+    let range = requested.unwrap_or_else(|| ByteRange::full(total_length));
+    let bytes_served = METRICS
+        .bytes_served
+        .with_label_values(&[&context.version_name]);
+    let stream = file_stream(file, range)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .inspect(move |data| {
+            if let Ok(data) = data {
+                bytes_served.inc_by(data.len() as u64);
+            }
+        });
+
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(CONTENT_LENGTH, range.len().into());
+    if let Some(encoding) = encoding {
+        let iv = format!("{:032x}", u128::from_be_bytes(key.iv(chunk_data)));
+        headers.insert(
+            CHUNK_COMPRESSION,
+            HeaderValue::from_static(encoding.compression.name()),
+        );
+        headers.insert(
+            CHUNK_IV,
+            HeaderValue::from_str(&iv).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+        // It's the uncompressed chunk the checksum is for
+        headers.remove(ETAG);
+    }
+    let status = match requested {
+        Some(range) => {
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&range.content_range(total_length))
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => StatusCode::OK,
+    };
+    let body = Body::from_stream(SemaphoreStream::new(stream, permit));
+    Ok(Some((status, headers, body).into_response()))
+}
+/**
+Streams `range` of a file that's sent as is, such as a cached chunk that's
+already encrypted. Whole cached chunks usually skip this, see `sendfile`
+*/
+pub async fn file_stream(
+    mut file: File,
+    range: ByteRange,
+) -> Result<impl Stream<Item = Result<Bytes, Error>> + Send + 'static, Error> {
+    file.seek(SeekFrom::Start(range.start as u64)).await?;
+    Ok(ReaderStream::new(file.take(range.len() as u64)))
+}
+/**
+The compressions offered for a version, and the one the client picked
+*/
+fn negotiate_compression(
+    state: &AppState,
+    context: &DownloadContext,
+    headers: &HeaderMap,
+) -> (Vec<Compression>, Option<Compression>) {
+    let offered = state
+        .config
+        .get()
+        .compression
+        .offered_for(&context.game_id, &context.version_name)
+        .to_vec();
+    let compression = match headers
+        .get(ACCEPT_CHUNK_COMPRESSION)
+        .map(HeaderValue::to_str)
+    {
+        Some(Ok(accepted)) => Compression::negotiate(&offered, accepted),
+        _ => None,
+    };
+    (offered, compression)
 }
-async fn acquire_permit<'a>() -> SemaphorePermit<'a> {
-    return GLOBAL_CONTEXT_SEMAPHORE
-        .acquire()
+/**
+Waits for a file permit for every file segment `ranges` will open
+*/
+pub(crate) async fn acquire_file_permits(
+    chunk_data: &ChunkData,
+    ranges: &[ByteRange],
+) -> Result<SemaphorePermit<'static>, StatusCode> {
+    let segment_count: usize = ranges
+        .iter()
+        .map(|range| file_segments(&chunk_data.files, *range).len())
+        .sum();
+    if segment_count >= *SEMPAHORE_COUNT {
+        return Err(StatusCode::INSUFFICIENT_STORAGE);
+    }
+    FILE_SEMAPHORE
+        .acquire_many(
+            segment_count
+                .try_into()
+                .map_err(|_| StatusCode::INSUFFICIENT_STORAGE)?,
+        )
         .await
-        .expect("failed to acquire semaphore");
+        .map_err(|_| StatusCode::INSUFFICIENT_STORAGE)
+}
+/**
+Builds a multipart/byteranges body out of several ranges, returning it
+alongside its total length
+*/
+async fn multipart_stream(
+    context: &DownloadContext,
+    chunk_data: &ChunkData,
+    ranges: &[ByteRange],
+    total_length: usize,
+    boundary: &str,
+    buffer_size: usize,
+) -> Result<
+    (
+        impl Stream<Item = Result<Bytes, Error>> + Send + 'static,
+        usize,
+    ),
+    StatusCode,
+> {
+    let mut parts: Vec<BoxStream<'static, Result<Bytes, Error>>> =
+        Vec::with_capacity(ranges.len() * 2 + 1);
+    let mut content_length = 0;
+
+    for range in ranges {
+        let part_header = Bytes::from(format!(
+            "\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(total_length)
+        ));
+        content_length += part_header.len() + range.len();
+        parts.push(stream::once(ready(Ok(part_header))).boxed());
+        parts.push(
+            range_stream(context, chunk_data, *range, None, buffer_size)
+                .await?
+                .boxed(),
+        );
+    }
+    let trailer = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+    content_length += trailer.len();
+    parts.push(stream::once(ready(Ok(trailer))).boxed());
+
+    Ok((stream::iter(parts).flatten(), content_length))
 }
 /**
- * Needs to be cloned for reference reasons
- */
-fn lookup_chunk(
+Opens the files backing `range` and returns the encrypted bytes for it.
+The keystream is seeked to the start of the range, so the output is
+identical to the same slice of a full download. With a `check`, the
+plaintext is verified on the way, so `range` must cover the whole chunk
+*/
+async fn range_stream(
+    context: &DownloadContext,
+    chunk_data: &ChunkData,
+    range: ByteRange,
+    check: Option<ChunkCheck>,
+    buffer_size: usize,
+) -> Result<impl Stream<Item = Result<Bytes, Error>> + Send + 'static, StatusCode> {
+    let readers = match check {
+        Some(check) => {
+            let plaintext = plaintext_stream(context, chunk_data, range, Some(check)).await?;
+            vec![Box::new(StreamReader::new(plaintext)) as Box<dyn MinimumFileObject>]
+        }
+        None => plaintext_readers(context, chunk_data, range).await?,
+    };
+    Ok(encrypt(
+        context,
+        chunk_data,
+        range.start,
+        readers,
+        buffer_size,
+    ))
+}
+/**
+Opens a reader for each file segment backing `range`, in order
+*/
+async fn plaintext_readers(
+    context: &DownloadContext,
+    chunk_data: &ChunkData,
+    range: ByteRange,
+) -> Result<Vec<Box<dyn MinimumFileObject>>, StatusCode> {
+    let segments = file_segments(&chunk_data.files, range);
+    let mut readers = Vec::with_capacity(segments.len());
+
+    for segment in segments {
+        readers.push(
+            get_file_reader(
+                context,
+                segment.filename.to_owned(),
+                segment.start,
+                segment.end,
+            )
+            .await?,
+        );
+    }
+    Ok(readers)
+}
+pub(crate) async fn plaintext_stream(
+    context: &DownloadContext,
+    chunk_data: &ChunkData,
+    range: ByteRange,
+    check: Option<ChunkCheck>,
+) -> Result<ByteStream, StatusCode> {
+    let readers = plaintext_readers(context, chunk_data, range).await?;
+    let stream = stream::iter(readers.into_iter().map(ReaderStream::new)).flatten();
+    Ok(match check {
+        Some(check) => VerifyingStream::new(stream, check).boxed(),
+        None => stream.boxed(),
+    })
+}
+/**
+Encrypts what `readers` read, with the keystream seeked to `offset`
+*/
+fn encrypt(
+    context: &DownloadContext,
+    chunk_data: &ChunkData,
+    offset: usize,
+    readers: Vec<Box<dyn MinimumFileObject>>,
+    buffer_size: usize,
+) -> impl Stream<Item = Result<Bytes, Error>> + Send + 'static {
+    let mut cipher = Aes128Ctr64LE::new(&context.manifest.key.into(), &chunk_data.iv.into());
+    cipher.seek(offset as u64);
+    let bytes_served = METRICS
+        .bytes_served
+        .with_label_values(&[&context.version_name]);
+    EncryptingStream::new(readers, cipher, buffer_size).inspect(move |data| {
+        if let Ok(data) = data {
+            bytes_served.inc_by(data.len() as u64);
+        }
+    })
+}
+fn lookup_chunk<'a>(
     chunk_id: &str,
-    context: &RefMut<'_, (String, String), DownloadContext>,
-) -> Result<ChunkData, StatusCode> {
+    context: &'a DownloadContext,
+) -> Result<&'a ChunkData, StatusCode> {
     context
         .manifest
         .chunks
         .get(chunk_id)
-        .cloned()
         .ok_or(StatusCode::NOT_FOUND)
 }
 async fn get_file_reader(
-    context: &mut RefMut<'_, (String, String), DownloadContext>,
+    context: &DownloadContext,
     relative_filename: String,
     start: usize,
     end: usize,
 ) -> Result<Box<dyn MinimumFileObject>, StatusCode> {
     context
-        .backend
-        .reader(
-            &VersionFile {
-                relative_filename: relative_filename.clone(),
-                permission: 0,
-                size: 0,
-            },
-            start as u64,
-            end as u64,
-        )
+        .reader(relative_filename.clone(), start as u64, end as u64)
         .await
         .map_err(|v| {
             error!("reader error for '{relative_filename}': {v:?}");
             StatusCode::INTERNAL_SERVER_ERROR
         })
 }
-async fn get_or_create_context<'a>(
+pub(crate) async fn get_or_create_context(
     state: &Arc<AppState>,
-    context_cache: &'a DashMap<(String, String), DownloadContext>,
     game_id: String,
     version_name: String,
-) -> Result<RefMut<'a, (String, String), DownloadContext>, StatusCode> {
+) -> Result<Arc<DownloadContext>, StatusCode> {
     let key = (game_id.clone(), version_name.clone());
 
-    if let Some(context) = context_cache.get_mut(&key) {
-        Ok(context)
-    } else {
-        let permit = acquire_permit().await;
-
-        // Check if it's been done while we've been sitting here
-        if let Some(already_done) = context_cache.get_mut(&key) {
-            Ok(already_done)
-        } else {
-            info!("generating context for {game_id}...");
-            let context_result =
-                create_download_context(state, game_id.clone(), version_name.clone()).await?;
-
-            state.context_cache.insert(key.clone(), context_result);
-
-            info!("continuing download for {game_id}");
-
-            drop(permit);
-
-            Ok(context_cache.get_mut(&key).unwrap())
-        }
-    }
+    state
+        .context_cache
+        .get_or_create(key.clone(), || {
+            let state = state.clone();
+            let epoch = state.context_cache.epochs().get(&key);
+            async move {
+                info!("generating context for {game_id}...");
+                let context = create_download_context(&state, game_id.clone(), version_name, epoch)
+                    .await
+                    .map_err(StatusCode::from)?;
+                let context = state.context_cache.insert(&key, context);
+                info!("continuing download for {game_id}");
+                Ok(context)
+            }
+        })
+        .await
 }
@@ -0,0 +1,274 @@
+use std::{
+    io::Error,
+    pin::Pin,
+    sync::{Arc, LazyLock},
+    task::{Context, Poll, ready},
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use dashmap::DashSet;
+use droplet_rs::manifest::ChunkData;
+use futures_util::Stream;
+use log::{debug, error, info, warn};
+use pin_project_lite::pin_project;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    DownloadContext,
+    downloads::{
+        cache::ContextKey,
+        serve::{file_permit, get_or_create_context},
+    },
+    metrics::METRICS,
+    proto::{core::DropBoundType, version::ChunkCorrupt},
+    server::DropServer,
+    state::AppState,
+};
+
+/**
+A chunk to check against its checksum, and who to tell if it's wrong
+*/
+pub struct ChunkCheck {
+    server: Arc<DropServer>,
+    game_id: String,
+    version_id: String,
+    chunk_id: String,
+    expected: String,
+}
+
+impl ChunkCheck {
+    #[must_use]
+    pub fn new(
+        server: Arc<DropServer>,
+        context: &DownloadContext,
+        chunk_id: String,
+        chunk: &ChunkData,
+    ) -> Self {
+        Self {
+            server,
+            game_id: context.game_id.clone(),
+            version_id: context.version_name.clone(),
+            chunk_id,
+            expected: chunk.checksum.clone(),
+        }
+    }
+
+    /**
+    Compares the hash of the chunk's plaintext against its checksum,
+    reporting it if they differ
+    */
+    pub(crate) fn finish(self, hasher: Sha256, source: &'static str) -> bool {
+        let actual = format!("{:x}", hasher.finalize());
+        if actual.eq_ignore_ascii_case(&self.expected) {
+            METRICS
+                .chunk_verifications
+                .with_label_values(&[source, "ok"])
+                .inc();
+            return true;
+        }
+
+        METRICS
+            .chunk_verifications
+            .with_label_values(&[source, "corrupt"])
+            .inc();
+        error!(
+            "chunk {} of {}/{} is corrupt: expected {}, read {actual}",
+            self.chunk_id, self.game_id, self.version_id, self.expected
+        );
+
+        let mut corrupt = ChunkCorrupt::new();
+        corrupt.game_id = self.game_id;
+        corrupt.version_id = self.version_id;
+        corrupt.chunk_id = self.chunk_id;
+        corrupt.expected = self.expected;
+        corrupt.actual = actual;
+        let server = self.server.clone();
+        self.server.spawn_tracked(async move {
+            if let Err(err) = server
+                .send_message(DropBoundType::CHUNK_CORRUPT, corrupt, None)
+                .await
+            {
+                debug!("didn't report corrupt chunk to drop: {err:#}");
+            }
+        });
+        false
+    }
+}
+
+pin_project! {
+    /**
+    Hashes a chunk's plaintext as it streams. The last piece is held back
+    until the hash is checked, so a corrupt chunk never reaches the client
+    whole, and the response is cut off instead
+    */
+    pub struct VerifyingStream<S> {
+        #[pin]
+        stream: S,
+        hasher: Sha256,
+        check: Option<ChunkCheck>,
+        held: Option<Bytes>,
+    }
+}
+
+impl<S> VerifyingStream<S> {
+    pub fn new(stream: S, check: ChunkCheck) -> Self {
+        Self {
+            stream,
+            hasher: Sha256::new(),
+            check: Some(check),
+            held: None,
+        }
+    }
+}
+
+impl<S> Stream for VerifyingStream<S>
+where
+    S: Stream<Item = Result<Bytes, Error>>,
+{
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            // Already checked, so only the held back piece is left
+            let Some(check) = this.check.take() else {
+                return Poll::Ready(this.held.take().map(Ok));
+            };
+
+            match ready!(this.stream.as_mut().poll_next(cx)) {
+                Some(Ok(bytes)) => {
+                    *this.check = Some(check);
+                    this.hasher.update(&bytes);
+                    if let Some(previous) = this.held.replace(bytes) {
+                        return Poll::Ready(Some(Ok(previous)));
+                    }
+                }
+                Some(Err(err)) => {
+                    *this.check = Some(check);
+                    return Poll::Ready(Some(Err(err)));
+                }
+                None => {
+                    let hasher = std::mem::take(this.hasher);
+                    if !check.finish(hasher, "serve") {
+                        this.held.take();
+                        return Poll::Ready(Some(Err(Error::other("chunk failed verification"))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+static SCRUBBING: LazyLock<DashSet<ContextKey>> = LazyLock::new(DashSet::new);
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScrubReport {
+    pub chunks: usize,
+    pub corrupt: Vec<String>,
+    /// Chunks that couldn't be read at all
+    pub unreadable: Vec<String>,
+}
+
+/**
+Starts checking every chunk of a version against its checksum in the
+background, returning false if that version is already being scrubbed
+*/
+pub fn start_scrub(state: Arc<AppState>, key: ContextKey) -> bool {
+    if !SCRUBBING.insert(key.clone()) {
+        return false;
+    }
+    let server = state.server.clone();
+    server.spawn_tracked(async move {
+        let (game_id, version_id) = key.clone();
+        match scrub(&state, key.clone()).await {
+            Ok(report) => info!(
+                "scrubbed {} chunks of {game_id}/{version_id}: {} corrupt, {} unreadable",
+                report.chunks,
+                report.corrupt.len(),
+                report.unreadable.len()
+            ),
+            Err(err) => warn!("failed to scrub {game_id}/{version_id}: {err:#}"),
+        }
+        SCRUBBING.remove(&key);
+    });
+    true
+}
+
+/**
+Reads every chunk of a version, one file at a time so downloads keep
+most of the file permits. Stops between chunks once shutdown starts
+*/
+pub async fn scrub(
+    state: &Arc<AppState>,
+    (game_id, version_id): ContextKey,
+) -> Result<ScrubReport, anyhow::Error> {
+    let context = get_or_create_context(state, game_id, version_id)
+        .await
+        .map_err(|status| anyhow!("failed to create context: {status}"))?;
+
+    let mut report = ScrubReport::default();
+    let mut buffer = vec![0; 1024 * 1024];
+    for (chunk_id, chunk) in &context.manifest.chunks {
+        if state.server.is_shutting_down() {
+            return Err(anyhow!(
+                "stopped after {} chunks, shutting down",
+                report.chunks
+            ));
+        }
+        report.chunks += 1;
+        let hasher = match hash_chunk(&context, chunk, &mut buffer).await {
+            Ok(hasher) => hasher,
+            Err(err) => {
+                warn!("failed to read chunk {chunk_id} while scrubbing: {err:#}");
+                METRICS
+                    .chunk_verifications
+                    .with_label_values(&["scrub", "unreadable"])
+                    .inc();
+                report.unreadable.push(chunk_id.clone());
+                continue;
+            }
+        };
+
+        let check = ChunkCheck::new(state.server.clone(), &context, chunk_id.clone(), chunk);
+        if !check.finish(hasher, "scrub") {
+            report.corrupt.push(chunk_id.clone());
+        }
+    }
+    Ok(report)
+}
+
+async fn hash_chunk(
+    context: &DownloadContext,
+    chunk: &ChunkData,
+    buffer: &mut [u8],
+) -> Result<Sha256, anyhow::Error> {
+    let mut hasher = Sha256::new();
+    for file in &chunk.files {
+        let _permit = file_permit()
+            .await
+            .ok_or_else(|| anyhow!("file permits closed"))?;
+        let mut reader = context
+            .reader(
+                file.filename.clone(),
+                file.start as u64,
+                (file.start + file.length) as u64,
+            )
+            .await?;
+
+        let mut remaining = file.length;
+        while remaining > 0 {
+            let read = reader.read(buffer).await?;
+            if read == 0 {
+                return Err(anyhow!("{} is shorter than expected", file.filename));
+            }
+            let read = read.min(remaining);
+            hasher.update(&buffer[..read]);
+            remaining -= read;
+        }
+    }
+    Ok(hasher)
+}
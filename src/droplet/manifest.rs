@@ -1,14 +1,19 @@
 use std::{
     path::PathBuf,
-    sync::{Arc, LazyLock},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
 };
 
-use log::info;
+use anyhow::anyhow;
 use protobuf::Message;
 use serde_json::json;
-use tokio::{spawn, sync::Semaphore};
+use tokio::spawn;
 
 use crate::{
+    metrics::METRICS,
     proto::{
         core::{DropBoundType, TorrentialBound},
         droplet::{GenerateManifest, ManifestComplete, ManifestLog, ManifestProgress},
@@ -16,23 +21,48 @@ use crate::{
     server::DropServer,
 };
 
-static READER_SEMAPHORE: LazyLock<Semaphore> = LazyLock::new(|| {
-    let cores = std::env::var("READER_THREADS")
-        .ok()
-        .and_then(|v| str::parse::<usize>(&v).ok())
-        .unwrap_or(num_cpus::get() / 2);
-    info!("using {cores} import threads");
-    Semaphore::new(cores)
-});
+static MANIFESTS_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+#[must_use]
+pub fn manifests_in_flight() -> usize {
+    MANIFESTS_IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/**
+Tracks a running generation, recording how long it took when dropped
+*/
+struct InFlightGuard {
+    started: Instant,
+}
+
+impl InFlightGuard {
+    fn new() -> Self {
+        MANIFESTS_IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        MANIFESTS_IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        METRICS
+            .manifest_generation
+            .observe(self.started.elapsed().as_secs_f64());
+    }
+}
 
 pub async fn generate_manifest_rpc(
     server: Arc<DropServer>,
     message: TorrentialBound,
 ) -> Result<(), anyhow::Error> {
+    let _in_flight = InFlightGuard::new();
     let manifest_message = GenerateManifest::parse_from_bytes(&message.data)?;
 
-    let manifest = droplet_rs::manifest::generate_manifest_rusty(
-        &PathBuf::from(manifest_message.version_dir),
+    let version_dir = PathBuf::from(manifest_message.version_dir);
+    let generation = droplet_rs::manifest::generate_manifest_rusty(
+        &version_dir,
         |progress| {
             let mut progress_message = ManifestProgress::new();
             progress_message.progress = progress;
@@ -61,9 +91,15 @@ pub async fn generate_manifest_rpc(
                     .await;
             });
         },
-        Some(&READER_SEMAPHORE),
-    )
-    .await?;
+        Some(server.reader_semaphore()),
+    );
+
+    let manifest = tokio::select! {
+        manifest = generation => manifest?,
+        () = server.shutting_down() => {
+            return Err(anyhow!("torrential is shutting down"));
+        }
+    };
 
     let mut manifest_complete = ManifestComplete::new();
     manifest_complete.manifest = json!(manifest).to_string();
@@ -1,102 +1,177 @@
-use std::{
-    env::set_current_dir,
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{env::set_current_dir, net::SocketAddr, sync::Arc};
 
-use axum::{
-    Router,
-    routing::{get, post},
-};
-use dashmap::DashMap;
-use log::info;
+use axum::Router;
+use clap::Parser;
+use dashmap::DashSet;
+use log::{LevelFilter, error, info, warn};
 use simple_logger::SimpleLogger;
-use tokio::{runtime::Handle, spawn, time};
+use tokio::{runtime::Handle, spawn, sync::mpsc, time::timeout};
+use tokio_util::sync::CancellationToken;
 use torrential::{
-    downloads::{handlers, serve},
+    app::{setup_admin_app, setup_app},
+    config::{Args, Config, SharedConfig},
+    downloads::{
+        cache::{ContextCache, expiry_subroutine},
+        prewarm::prewarm_subroutine,
+        sendfile,
+    },
     server::create_drop_server,
     state::AppState,
 };
 
-const CONTEXT_TTL: u64 = 10 * 60;
-
 #[tokio::main]
 async fn main() {
-    initialise_logger();
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration: {err:#}");
+            std::process::exit(1);
+        }
+    };
 
-    if let Ok(working_directory) = std::env::var("WORKING_DIRECTORY") {
-        info!("moving to working directory {working_directory}");
+    initialise_logger(config.log_level);
+    info!("using configuration: {config:?}");
+
+    if let Some(working_directory) = &config.working_directory {
+        info!(
+            "moving to working directory {}",
+            working_directory.display()
+        );
         set_current_dir(working_directory).expect("failed to change working directory");
     }
 
     let metrics = Handle::current().metrics();
     info!("using {} threads", metrics.num_workers());
 
-    let server = create_drop_server()
+    let bind_address = config.bind_address;
+    let admin_bind_address = config.admin_bind_address;
+    let config = Arc::new(SharedConfig::new(args, config));
+
+    #[cfg(unix)]
+    spawn(reload_on_sighup(config.clone()));
+
+    let context_cache = Arc::new(ContextCache::new(config.clone()));
+    context_cache.chunk_cache().load().await;
+    let (prewarm_requests, prewarm_receiver) = mpsc::unbounded_channel();
+    let server = create_drop_server(config.clone(), context_cache.clone(), prewarm_requests)
         .await
-        .expect("failed to connect to drop server");
+        .expect("failed to start drop link");
 
     let shared_state = Arc::new(AppState {
-        context_cache: DashMap::new(),
+        config: config.clone(),
+        context_cache,
         server,
+        library_dirs: DashSet::new(),
     });
 
-    let interval_shared_state = shared_state.clone();
+    spawn(expiry_subroutine(shared_state.clone()));
+    spawn(prewarm_subroutine(shared_state.clone(), prewarm_receiver));
 
+    let app = setup_app(shared_state.clone());
+    let admin_app = setup_admin_app(shared_state.clone());
+
+    serve(
+        (app, bind_address),
+        (admin_app, admin_bind_address),
+        shared_state,
+    )
+    .await
+    .expect("failed to serve app");
+}
+
+#[cfg(unix)]
+async fn reload_on_sighup(config: Arc<SharedConfig>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    while hangups.recv().await.is_some() {
+        info!("reloading configuration after SIGHUP");
+        if let Err(err) = config.reload() {
+            error!("failed to reload configuration: {err:#}");
+        }
+    }
+}
+
+/**
+Serves the depot and admin apps until SIGTERM (or Ctrl-C), then stops
+accepting connections and gives the downloads in progress up to
+`shutdown_timeout` to finish
+*/
+async fn serve(
+    (app, bind_address): (Router, SocketAddr),
+    (admin_app, admin_bind_address): (Router, SocketAddr),
+    state: Arc<AppState>,
+) -> Result<(), std::io::Error> {
+    let listener = tokio::net::TcpListener::bind(bind_address)
+        .await
+        .expect("failed to bind tcp server");
+    info!("started depot server on {bind_address}");
+    let admin_listener = tokio::net::TcpListener::bind(admin_bind_address)
+        .await
+        .expect("failed to bind admin server");
+    info!("started admin server on {admin_bind_address}");
+
+    let stopping = CancellationToken::new();
+    let admin_stopping = stopping.clone().cancelled_owned();
     spawn(async move {
-        let shared_state = interval_shared_state;
-        let mut interval = time::interval(Duration::from_mins(1));
-
-        loop {
-            interval.tick().await;
-            let keys = shared_state
-                .context_cache
-                .iter()
-                .map(|v| v.key().clone())
-                .collect::<Vec<(String, String)>>();
-            for key in keys {
-                let last_access = if let Some(context) = shared_state.context_cache.get(&key) {
-                    context.last_access()
-                } else {
-                    Instant::now()
-                };
-                if last_access.elapsed().as_secs() >= CONTEXT_TTL {
-                    shared_state.context_cache.remove(&key);
-                    info!("cleaned context: {key:?}");
-                }
-            }
+        let serving = axum::serve(admin_listener, admin_app).with_graceful_shutdown(admin_stopping);
+        if let Err(err) = serving.await {
+            error!("admin server failed: {err}");
         }
     });
+    let serving = sendfile::serve(listener, app, state.clone(), stopping.clone());
+    tokio::pin!(serving);
 
-    let app = setup_app(shared_state);
+    tokio::select! {
+        () = &mut serving => return Ok(()),
+        () = shutdown_signal() => {}
+    }
 
-    serve(app).await.expect("failed to serve app");
-}
+    let drain = state.config.get().shutdown_timeout();
+    info!(
+        "shutting down, giving downloads in progress {}s to finish",
+        drain.as_secs()
+    );
+    stopping.cancel();
 
-fn setup_app(shared_state: Arc<AppState>) -> Router {
-    Router::new()
-        .route(
-            "/api/v1/depot/content/{game_id}/{version_name}/{chunk_id}",
-            get(serve::serve_file),
-        )
-        .route("/api/v1/depot/manifest.json", get(handlers::manifest))
-        .route("/api/v1/depot/speedtest", get(handlers::speedtest))
-        .route("/healthcheck", get(handlers::healthcheck))
-        .route("/invalidate", post(handlers::invalidate))
-        .with_state(shared_state)
+    let (result, ()) = tokio::join!(timeout(drain, serving), state.server.shutdown(drain));
+    if result.is_ok() {
+        info!("all downloads finished");
+        Ok(())
+    } else {
+        warn!("gave up waiting for downloads to finish");
+        Ok(())
+    }
 }
 
-async fn serve(app: Router) -> Result<(), std::io::Error> {
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:5000")
-        .await
-        .expect("failed to bind tcp server");
-    info!("started depot server");
-    axum::serve(listener, app).await
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        signal(SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
 }
 
-fn initialise_logger() {
+/**
+The logger lets everything through, and `log`'s max level does the
+filtering, so the level can be changed on reload
+*/
+fn initialise_logger(level: LevelFilter) {
     SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
+        .with_level(LevelFilter::Trace)
         .init()
         .expect("failed to init logger");
+    log::set_max_level(level);
 }
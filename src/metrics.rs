@@ -0,0 +1,169 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder, core::Collector,
+};
+
+use crate::{downloads::serve::file_permits, state::AppState};
+
+/**
+Everything exported on `/metrics`. Registered together, so every series
+shows up from the first scrape rather than the first time it's used
+*/
+pub struct Metrics {
+    registry: Registry,
+    pub bytes_served: IntCounterVec,
+    pub chunk_requests: HistogramVec,
+    pub context_cache: IntCounterVec,
+    pub context_evictions: IntCounterVec,
+    pub chunk_verifications: IntCounterVec,
+    pub chunk_cache: IntCounterVec,
+    chunk_cache_bytes: IntGauge,
+    cached_contexts: IntGauge,
+    cached_chunks: IntGauge,
+    permits: IntGaugeVec,
+    pub drop_rpc: HistogramVec,
+    pub drop_rpc_failures: IntCounterVec,
+    pub drop_messages_sent: IntCounterVec,
+    pub manifest_generation: Histogram,
+}
+
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("failed to register metrics"));
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("torrential".to_owned()), None)?;
+
+        let metrics = Self {
+            bytes_served: IntCounterVec::new(
+                Opts::new("bytes_served_total", "Chunk bytes sent to clients"),
+                // Only versions Drop knows about get this far. Game IDs come
+                // from the request path unchecked, so they'd be unbounded
+                &["version"],
+            )?,
+            chunk_requests: HistogramVec::new(
+                HistogramOpts::new(
+                    "chunk_request_duration_seconds",
+                    "Time to start responding to chunk requests, by status",
+                ),
+                &["status"],
+            )?,
+            context_cache: IntCounterVec::new(
+                Opts::new(
+                    "context_cache_lookups_total",
+                    "Download context cache lookups",
+                ),
+                &["result"],
+            )?,
+            context_evictions: IntCounterVec::new(
+                Opts::new(
+                    "context_cache_evictions_total",
+                    "Download contexts removed from the cache",
+                ),
+                &["reason"],
+            )?,
+            chunk_verifications: IntCounterVec::new(
+                Opts::new(
+                    "chunk_verifications_total",
+                    "Chunks checked against their checksums, by where and how it went",
+                ),
+                &["source", "result"],
+            )?,
+            chunk_cache: IntCounterVec::new(
+                Opts::new(
+                    "chunk_cache_events_total",
+                    "Encrypted chunk cache hits, misses and evictions",
+                ),
+                &["event"],
+            )?,
+            chunk_cache_bytes: IntGauge::new(
+                "chunk_cache_bytes",
+                "Bytes of encrypted chunks cached on disk",
+            )?,
+            cached_contexts: IntGauge::new("cached_contexts", "Download contexts in the cache")?,
+            cached_chunks: IntGauge::new(
+                "cached_chunks",
+                "Manifest chunks held by cached download contexts",
+            )?,
+            permits: IntGaugeVec::new(
+                Opts::new("semaphore_permits", "Semaphore permits, by state"),
+                &["semaphore", "state"],
+            )?,
+            drop_rpc: HistogramVec::new(
+                HistogramOpts::new(
+                    "drop_rpc_duration_seconds",
+                    "Round trip of answered queries to Drop",
+                ),
+                &["type"],
+            )?,
+            drop_rpc_failures: IntCounterVec::new(
+                Opts::new("drop_rpc_failures_total", "Queries to Drop that failed"),
+                &["type"],
+            )?,
+            drop_messages_sent: IntCounterVec::new(
+                Opts::new("drop_messages_sent_total", "Messages sent to Drop"),
+                &["type"],
+            )?,
+            manifest_generation: Histogram::with_opts(
+                HistogramOpts::new(
+                    "manifest_generation_duration_seconds",
+                    "Time taken to generate manifests",
+                )
+                .buckets(prometheus::exponential_buckets(1.0, 2.0, 12)?),
+            )?,
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 14] = [
+            Box::new(metrics.bytes_served.clone()),
+            Box::new(metrics.chunk_requests.clone()),
+            Box::new(metrics.context_cache.clone()),
+            Box::new(metrics.context_evictions.clone()),
+            Box::new(metrics.chunk_verifications.clone()),
+            Box::new(metrics.chunk_cache.clone()),
+            Box::new(metrics.chunk_cache_bytes.clone()),
+            Box::new(metrics.cached_contexts.clone()),
+            Box::new(metrics.cached_chunks.clone()),
+            Box::new(metrics.permits.clone()),
+            Box::new(metrics.drop_rpc.clone()),
+            Box::new(metrics.drop_rpc_failures.clone()),
+            Box::new(metrics.drop_messages_sent.clone()),
+            Box::new(metrics.manifest_generation.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+
+        Ok(metrics)
+    }
+
+    fn set_permits(&self, semaphore: &str, (available, total): (usize, usize)) {
+        let to_gauge = |v: usize| i64::try_from(v).unwrap_or(i64::MAX);
+        self.permits
+            .with_label_values(&[semaphore, "available"])
+            .set(to_gauge(available));
+        self.permits
+            .with_label_values(&[semaphore, "total"])
+            .set(to_gauge(total));
+    }
+
+    /**
+    Samples the gauges and renders everything in the Prometheus text format
+    */
+    pub fn render(&self, state: &AppState) -> Result<String, prometheus::Error> {
+        self.cached_contexts
+            .set(i64::try_from(state.context_cache.len()).unwrap_or(i64::MAX));
+        self.cached_chunks
+            .set(i64::try_from(state.context_cache.chunks()).unwrap_or(i64::MAX));
+        self.chunk_cache_bytes
+            .set(i64::try_from(state.context_cache.chunk_cache().bytes()).unwrap_or(i64::MAX));
+        self.set_permits("file", file_permits());
+        self.set_permits("reader", state.server.reader_permits());
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+use hmac::{Hmac, Mac};
+use protobuf::Message;
+use sha2::Sha256;
+
+use crate::{
+    proto::core::{
+        DropBoundType, HandshakeChallenge, HandshakeResponse, TorrentialBound, TorrentialBoundType,
+    },
+    server::{
+        encode_message, read_frame,
+        transport::{ReadStream, WriteStream},
+        write_frame,
+    },
+};
+
+const NONCE_LENGTH: usize = 32;
+
+/**
+Makes the peer prove it knows the link secret. A no-op without one, which
+config validation only allows with `link.allow_unauthenticated`
+*/
+pub async fn authenticate(
+    secret: Option<&[u8]>,
+    buffered_reader: &mut ReadStream,
+    write: &mut WriteStream,
+) -> Result<(), anyhow::Error> {
+    match secret {
+        Some(secret) => handshake(secret, buffered_reader, write).await,
+        None => Ok(()),
+    }
+}
+
+async fn handshake(
+    secret: &[u8],
+    buffered_reader: &mut ReadStream,
+    write: &mut WriteStream,
+) -> Result<(), anyhow::Error> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    getrandom::fill(&mut nonce).map_err(|err| anyhow!("failed to generate nonce: {err:?}"))?;
+
+    let mut challenge = HandshakeChallenge::new();
+    challenge.nonce = nonce.to_vec();
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let buf = encode_message(
+        DropBoundType::HANDSHAKE_CHALLENGE,
+        &challenge,
+        message_id.clone(),
+    )?;
+    write_frame(write, &buf).await?;
+
+    let message = TorrentialBound::parse_from_bytes(&read_frame(buffered_reader).await?)?;
+    if message.type_.enum_value() != Ok(TorrentialBoundType::HANDSHAKE_RESPONSE)
+        || message.message_id != message_id
+    {
+        return Err(anyhow!(
+            "expected handshake response, got {:?}",
+            message.type_
+        ));
+    }
+    let response = HandshakeResponse::parse_from_bytes(&message.data)?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)?;
+    mac.update(&nonce);
+    mac.verify_slice(&response.mac)
+        .map_err(|_| anyhow!("handshake mac didn't match"))?;
+
+    Ok(())
+}
@@ -0,0 +1,394 @@
+use std::fmt::Display;
+
+use bytes::{Buf, BufMut, BytesMut};
+use protobuf::{CodedInputStream, rt::WireType};
+use tokio_util::codec::{Decoder, Encoder};
+
+const LENGTH_PREFIX: usize = size_of::<u64>();
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { length: u64, max: usize },
+    Io(std::io::Error),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { length, max } => {
+                write!(f, "frame of {length} bytes exceeds the {max} byte limit")
+            }
+            FrameError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/**
+Frames on the Drop link are a little-endian u64 length followed by that
+many bytes of protobuf. Used for both directions, though only what we
+read is limited to `max_frame_size`; what we send is as large as it has
+to be.
+
+Decoding never allocates more than `max_frame_size`, so arbitrary input
+can be thrown at it safely
+*/
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+    skip_oversized: bool,
+    skipping: Option<Skipping>,
+}
+
+/**
+A frame read off the link
+*/
+#[derive(Debug, PartialEq, Eq)]
+pub enum Frame {
+    Whole(BytesMut),
+    /// Over the size limit, so it was thrown away as it arrived. `head` is
+    /// its first few bytes, enough to tell which request it answered
+    Skipped {
+        length: u64,
+        head: BytesMut,
+    },
+}
+
+impl Frame {
+    /**
+    The message ID of a frame, which both directions' messages have as
+    their first field. Enough of a skipped one is kept to find it
+    */
+    #[must_use]
+    pub fn message_id(&self) -> Option<String> {
+        let (Frame::Whole(contents) | Frame::Skipped { head: contents, .. }) = self;
+        let mut input = CodedInputStream::from_bytes(contents);
+        while let Some(tag) = input.read_raw_tag_or_eof().ok()? {
+            let wire_type = WireType::new(tag & 7)?;
+            if tag >> 3 == 1 && wire_type == WireType::LengthDelimited {
+                return input.read_string().ok();
+            }
+            input.skip_field(wire_type).ok()?;
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Skipping {
+    length: u64,
+    remaining: u64,
+    head: BytesMut,
+}
+
+/// How much of a skipped frame is kept
+const SKIPPED_HEAD: usize = 1024;
+
+impl FrameCodec {
+    #[must_use]
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size,
+            skip_oversized: false,
+            skipping: None,
+        }
+    }
+
+    /**
+    Skips frames over the limit rather than failing, once the peer is
+    trusted not to send them on purpose. Otherwise the connection has to
+    go, as nothing after one can be read
+    */
+    pub fn skip_oversized(&mut self) {
+        self.skip_oversized = true;
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = FrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(mut skipping) = self.skipping.take() {
+            let available = usize::try_from(skipping.remaining)
+                .map_or(src.len(), |remaining| remaining.min(src.len()));
+            let mut discarded = src.split_to(available);
+            let kept = (SKIPPED_HEAD - skipping.head.len()).min(discarded.len());
+            skipping.head.extend_from_slice(&discarded.split_to(kept));
+            skipping.remaining -= available as u64;
+            if skipping.remaining > 0 {
+                self.skipping = Some(skipping);
+                return Ok(None);
+            }
+            return Ok(Some(Frame::Skipped {
+                length: skipping.length,
+                head: skipping.head,
+            }));
+        }
+
+        let Some(mut prefix) = src.get(..LENGTH_PREFIX) else {
+            return Ok(None);
+        };
+        let length = prefix.get_u64_le();
+        let Some(frame_length) = usize::try_from(length)
+            .ok()
+            .filter(|length| *length <= self.max_frame_size)
+        else {
+            if !self.skip_oversized {
+                return Err(FrameError::TooLarge {
+                    length,
+                    max: self.max_frame_size,
+                });
+            }
+            src.advance(LENGTH_PREFIX);
+            self.skipping = Some(Skipping {
+                length,
+                remaining: length,
+                head: BytesMut::new(),
+            });
+            return self.decode(src);
+        };
+
+        if src.len() < LENGTH_PREFIX + frame_length {
+            src.reserve(LENGTH_PREFIX + frame_length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_PREFIX);
+        Ok(Some(Frame::Whole(src.split_to(frame_length))))
+    }
+}
+
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = FrameError;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(LENGTH_PREFIX + item.len());
+        dst.put_u64_le(item.len() as u64);
+        dst.put_slice(item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt as _;
+    use protobuf::{EnumOrUnknown, Message};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use tokio_util::codec::FramedRead;
+
+    use super::*;
+    use crate::proto::core::{TorrentialBound, TorrentialBoundType};
+
+    const MAX: usize = 1024;
+
+    fn frame(contents: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        FrameCodec::new(MAX).encode(contents, &mut buffer).unwrap();
+        buffer
+    }
+
+    fn whole(frame: Option<Frame>) -> BytesMut {
+        match frame {
+            Some(Frame::Whole(frame)) => frame,
+            other => panic!("expected a whole frame, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let mut buffer = frame(b"first");
+        buffer.extend_from_slice(&frame(b"second"));
+        let mut codec = FrameCodec::new(MAX);
+
+        assert_eq!(whole(codec.decode(&mut buffer).unwrap()), &b"first"[..]);
+        assert_eq!(whole(codec.decode(&mut buffer).unwrap()), &b"second"[..]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decodes_zero_length_frames() {
+        let mut buffer = frame(b"");
+        assert_eq!(buffer.len(), LENGTH_PREFIX);
+        assert_eq!(
+            whole(FrameCodec::new(MAX).decode(&mut buffer).unwrap()),
+            &b""[..]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn refuses_oversized_frames_without_allocating() {
+        for length in [MAX as u64 + 1, u64::MAX] {
+            let mut buffer = BytesMut::new();
+            buffer.put_u64_le(length);
+            let capacity = buffer.capacity();
+
+            assert!(matches!(
+                FrameCodec::new(MAX).decode(&mut buffer),
+                Err(FrameError::TooLarge { length: found, max: MAX }) if found == length
+            ));
+            assert_eq!(buffer.capacity(), capacity);
+        }
+    }
+
+    /**
+    Only what's read is limited, as Drop has its own limits for what it
+    reads
+    */
+    #[test]
+    fn encodes_frames_of_any_size() {
+        let mut buffer = BytesMut::new();
+        FrameCodec::new(MAX)
+            .encode(&[7; MAX + 1], &mut buffer)
+            .unwrap();
+        assert_eq!(buffer.len(), LENGTH_PREFIX + MAX + 1);
+    }
+
+    /**
+    Once skipping, an oversized frame is read past in pieces without
+    holding onto it, and whatever follows it is read as usual
+    */
+    #[test]
+    fn skips_oversized_frames() {
+        let oversized = (0..=u8::MAX).cycle().take(4 * MAX).collect::<Vec<_>>();
+        let mut input = BytesMut::new();
+        FrameCodec::new(usize::MAX)
+            .encode(&oversized, &mut input)
+            .unwrap();
+        input.extend_from_slice(&frame(b"next"));
+
+        let mut codec = FrameCodec::new(MAX);
+        codec.skip_oversized();
+        let mut buffer = BytesMut::new();
+        let mut frames = Vec::new();
+        for piece in input.chunks(100) {
+            buffer.extend_from_slice(piece);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                frames.push(frame);
+            }
+            assert!(buffer.len() <= 100 + LENGTH_PREFIX);
+        }
+
+        assert_eq!(
+            frames,
+            [
+                Frame::Skipped {
+                    length: oversized.len() as u64,
+                    head: BytesMut::from(&oversized[..SKIPPED_HEAD]),
+                },
+                Frame::Whole(BytesMut::from(&b"next"[..])),
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_truncated_frames() {
+        let encoded = frame(b"split across reads");
+        let mut codec = FrameCodec::new(MAX);
+        let mut buffer = BytesMut::new();
+
+        for (i, byte) in encoded.iter().enumerate() {
+            assert!(codec.decode(&mut buffer).unwrap().is_none(), "{i}");
+            buffer.put_u8(*byte);
+        }
+        assert_eq!(
+            whole(codec.decode(&mut buffer).unwrap()),
+            &b"split across reads"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_on_a_frame_cut_off_by_eof() {
+        let whole = frame(b"never finished");
+        let truncated = &whole[..whole.len() - 1];
+        let mut reader = FramedRead::new(truncated, FrameCodec::new(MAX));
+
+        assert!(matches!(reader.next().await, Some(Err(FrameError::Io(_)))));
+    }
+
+    /**
+    Unknown message types have to get through decoding, so they can be
+    answered with an `RPC_ERROR` rather than dropping the connection
+    */
+    #[test]
+    fn keeps_unknown_message_types() {
+        let mut message = TorrentialBound::new();
+        message.message_id = "id".to_owned();
+        message.type_ = EnumOrUnknown::from_i32(9999);
+        let mut buffer = frame(&message.write_to_bytes().unwrap());
+
+        let decoded = whole(FrameCodec::new(MAX).decode(&mut buffer).unwrap());
+        let decoded = TorrentialBound::parse_from_bytes(&decoded).unwrap();
+        assert_eq!(decoded.message_id, "id");
+        assert_eq!(decoded.type_.enum_value(), Err(9999));
+    }
+
+    #[test]
+    fn finds_the_message_id_of_skipped_frames() {
+        let mut message = TorrentialBound::new();
+        message.message_id = "reply-to".to_owned();
+        message.type_ = EnumOrUnknown::new(TorrentialBoundType::VERSION_RESPONSE);
+        message.data = vec![0; 4 * MAX];
+        let encoded = message.write_to_bytes().unwrap();
+
+        let skipped = Frame::Skipped {
+            length: encoded.len() as u64,
+            head: BytesMut::from(&encoded[..SKIPPED_HEAD]),
+        };
+        assert_eq!(skipped.message_id().as_deref(), Some("reply-to"));
+
+        let garbage = Frame::Skipped {
+            length: 4,
+            head: BytesMut::from(&[0xff; 4][..]),
+        };
+        assert_eq!(garbage.message_id(), None);
+    }
+
+    /**
+    Arbitrary bytes, fed in arbitrary pieces, never panic, never produce a
+    frame over the limit, and never make the buffer grow much past it
+    */
+    #[test]
+    fn survives_arbitrary_input() {
+        let mut rng = StdRng::seed_from_u64(0x7075_6e6b);
+        for _ in 0..2000 {
+            let mut input = vec![0; rng.random_range(0..4 * MAX)];
+            rng.fill(&mut input[..]);
+            // Mostly plausible lengths, so frames actually get decoded
+            if rng.random_bool(0.75) && input.len() >= LENGTH_PREFIX {
+                let length = rng.random_range(0..2 * MAX) as u64;
+                input[..LENGTH_PREFIX].copy_from_slice(&length.to_le_bytes());
+            }
+
+            let mut codec = FrameCodec::new(MAX);
+            let mut buffer = BytesMut::new();
+            let mut rest = &input[..];
+            'feed: while !rest.is_empty() {
+                let (piece, remaining) = rest.split_at(rng.random_range(1..=rest.len()));
+                rest = remaining;
+                buffer.extend_from_slice(piece);
+                loop {
+                    match codec.decode(&mut buffer) {
+                        Ok(Some(frame)) => assert!(whole(Some(frame)).len() <= MAX),
+                        Ok(None) => break,
+                        Err(FrameError::TooLarge { length, .. }) => {
+                            assert!(length > MAX as u64);
+                            break 'feed;
+                        }
+                        Err(FrameError::Io(err)) => panic!("{err}"),
+                    }
+                }
+                assert!(buffer.capacity() <= LENGTH_PREFIX + MAX + input.len());
+            }
+        }
+    }
+}
@@ -15,23 +15,19 @@ pub async fn fetch_version_data(
 ) -> Result<VersionResponse, ErrorOption> {
     let mut query = VersionQuery::new();
     query.version_id = version_id;
-    let message_id = app_state
+    let response: VersionResponse = app_state
         .server
-        .send_message(DropBoundType::VERSION_QUERY, query, None)
+        .request(DropBoundType::VERSION_QUERY, query)
         .await?;
 
-    let response: VersionResponse = app_state.server.wait_for_message_id(&message_id).await?;
-
     Ok(response)
 }
 
 pub async fn fetch_instance_games(app_state: &AppState) -> Result<Vec<SkeletonGame>, ErrorOption> {
-    let message_id = app_state
+    let response: ServerGamesResponse = app_state
         .server
-        .send_message(DropBoundType::SERVER_GAMES_QUERY, ServerGamesQuery::new(), None)
+        .request(DropBoundType::SERVER_GAMES_QUERY, ServerGamesQuery::new())
         .await?;
 
-    let response: ServerGamesResponse = app_state.server.wait_for_message_id(&message_id).await?;
-
     Ok(response.games)
 }
@@ -0,0 +1,82 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use tokio::time::{sleep, timeout};
+
+use crate::{
+    proto::core::{DropBoundType, Heartbeat},
+    server::DropServer,
+};
+
+/**
+Long-lived subroutine that pings Drop, recording the round trip. Each ping
+has until the next one to be answered. After too many missed pings the
+connection is marked as down, which makes `recieve_subroutine` drop it
+and wait for Drop to reconnect
+*/
+pub async fn heartbeat_subroutine(server: Arc<DropServer>) -> ! {
+    let link = server.config.get().link.clone();
+    info!(
+        "pinging drop every {}s, allowing {} missed heartbeats",
+        link.heartbeat_interval, link.missed_heartbeats
+    );
+
+    loop {
+        // Read every time round, so reloads take effect on the next ping
+        let heartbeat_interval = server.config.get().link.heartbeat_interval();
+        sleep(heartbeat_interval).await;
+
+        let state = server.connection_state();
+        // Older Drop builds won't answer, so we don't hold it against them
+        if !state.connected || !state.peer.supports(DropBoundType::PING) {
+            continue;
+        }
+
+        // Not sent with `request`, as missed heartbeats are logged and
+        // counted here, not as failed RPCs
+        let sent = Instant::now();
+        let result = timeout(
+            heartbeat_interval,
+            server.exchange(DropBoundType::PING, &Heartbeat::new()),
+        )
+        .await;
+
+        match result {
+            Ok(Ok(_)) => record_pong(&server, state.generation, sent.elapsed()),
+            Ok(Err(err)) => record_missed(&server, state.generation, &err.to_string()),
+            Err(_) => record_missed(&server, state.generation, "timed out"),
+        }
+    }
+}
+
+fn record_pong(server: &DropServer, generation: u64, rtt: Duration) {
+    server.connection.send_if_modified(|state| {
+        if state.generation != generation {
+            return false;
+        }
+        state.rtt = Some(rtt);
+        state.missed_heartbeats = 0;
+        true
+    });
+}
+
+fn record_missed(server: &DropServer, generation: u64, reason: &str) {
+    server.connection.send_if_modified(|state| {
+        if state.generation != generation || !state.connected {
+            return false;
+        }
+        state.missed_heartbeats += 1;
+        warn!(
+            "drop missed heartbeat ({reason}), {} in a row",
+            state.missed_heartbeats
+        );
+        if state.missed_heartbeats >= server.config.get().link.missed_heartbeats {
+            warn!("treating drop link as dead");
+            state.connected = false;
+        }
+        true
+    });
+}
@@ -0,0 +1,164 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use protobuf::{Enum, Message};
+use tokio::time::{Instant, timeout_at};
+use tokio_util::codec::Encoder as _;
+
+use crate::{
+    proto::core::{DropBoundType, Hello, TorrentialBound, TorrentialBoundType},
+    server::{
+        encode_message, read_frame,
+        transport::{ReadStream, WriteStream},
+        write_frame,
+    },
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest version of Drop's side of the protocol we can talk to. v0 is
+/// Drop from before hellos, which never sends one, so for now that's every
+/// version and Drop's isn't checked against it
+pub const MINIMUM_PROTOCOL_VERSION: u32 = 0;
+
+/**
+What Drop handled before hellos, so what a v0 Drop is assumed to
+*/
+const LEGACY_TYPES: &[DropBoundType] = &[
+    DropBoundType::SERVER_GAMES_QUERY,
+    DropBoundType::VERSION_QUERY,
+    DropBoundType::RPC_ERROR,
+    DropBoundType::MANIFEST_PROGRESS,
+    DropBoundType::MANIFEST_LOG,
+    DropBoundType::MANIFEST_COMPLETE,
+    DropBoundType::ROOT_CA_COMPLETE,
+    DropBoundType::CLIENT_CERT_COMPLETE,
+    DropBoundType::HAS_BACKEND_COMPLETE,
+    DropBoundType::LIST_FILES_COMPLETE,
+    DropBoundType::PEEK_FILE_COMPLETE,
+];
+
+/**
+Message types we handle in `recieve_loop`, advertised to Drop
+*/
+pub const SUPPORTED_TYPES: &[TorrentialBoundType] = &[
+    TorrentialBoundType::ERROR,
+    TorrentialBoundType::SERVER_GAMES_RESPONSE,
+    TorrentialBoundType::VERSION_RESPONSE,
+    TorrentialBoundType::PONG,
+    TorrentialBoundType::GENERATE_MANIFEST,
+    TorrentialBoundType::GENERATE_ROOT_CA,
+    TorrentialBoundType::GENERATE_CLIENT_CERT,
+    TorrentialBoundType::HAS_BACKEND_QUERY,
+    TorrentialBoundType::LIST_FILES_QUERY,
+    TorrentialBoundType::PEEK_FILE_QUERY,
+    TorrentialBoundType::INVALIDATE,
+    TorrentialBoundType::PREWARM,
+];
+
+/**
+What the connected Drop instance told us about itself
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    pub protocol_version: u32,
+    pub supported_types: HashSet<DropBoundType>,
+}
+
+impl PeerInfo {
+    fn legacy() -> Self {
+        Self {
+            protocol_version: 0,
+            supported_types: LEGACY_TYPES.iter().copied().collect(),
+        }
+    }
+
+    #[must_use]
+    pub fn supports(&self, message_type: DropBoundType) -> bool {
+        self.supported_types.contains(&message_type)
+    }
+}
+
+/**
+Whether `message_type` is only ever sent in answer to a message from Drop.
+Drop asked for those, so they're sent whatever it listed in its hello
+*/
+#[must_use]
+pub fn is_reply(message_type: DropBoundType) -> bool {
+    matches!(
+        message_type,
+        DropBoundType::RPC_ERROR
+            | DropBoundType::MANIFEST_PROGRESS
+            | DropBoundType::MANIFEST_LOG
+            | DropBoundType::MANIFEST_COMPLETE
+            | DropBoundType::ROOT_CA_COMPLETE
+            | DropBoundType::CLIENT_CERT_COMPLETE
+            | DropBoundType::HAS_BACKEND_COMPLETE
+            | DropBoundType::LIST_FILES_COMPLETE
+            | DropBoundType::PEEK_FILE_COMPLETE
+            | DropBoundType::INVALIDATE_COMPLETE
+            | DropBoundType::PREWARM_COMPLETE
+    )
+}
+
+/**
+Sends our hello and waits for Drop's, refusing the connection if either
+side is too old for the other. A Drop that doesn't answer with a hello by
+`deadline`, the end of the handshake, is from before them, and is taken to
+speak v0
+*/
+pub async fn negotiate(
+    buffered_reader: &mut ReadStream,
+    write: &mut WriteStream,
+    deadline: Instant,
+) -> Result<PeerInfo, anyhow::Error> {
+    let mut hello = Hello::new();
+    hello.protocol_version = PROTOCOL_VERSION;
+    hello.minimum_protocol_version = MINIMUM_PROTOCOL_VERSION;
+    hello.supported_types = SUPPORTED_TYPES.iter().map(Enum::value).collect();
+
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let buf = encode_message(DropBoundType::HELLO, &hello, message_id)?;
+    timeout_at(deadline, write_frame(write, &buf))
+        .await
+        .map_err(|_| anyhow!("handshake timed out"))??;
+
+    let Ok(frame) = timeout_at(deadline, read_frame(buffered_reader)).await else {
+        return Ok(PeerInfo::legacy());
+    };
+    let frame = frame?;
+    let message = TorrentialBound::parse_from_bytes(&frame)?;
+    if message.type_.enum_value() != Ok(TorrentialBoundType::HELLO) {
+        // It's a real message from a v0 Drop, so it's left for `recieve_loop`
+        unread(buffered_reader, &frame)?;
+        return Ok(PeerInfo::legacy());
+    }
+    let peer_hello = Hello::parse_from_bytes(&message.data)?;
+
+    if peer_hello.minimum_protocol_version > PROTOCOL_VERSION {
+        return Err(anyhow!(
+            "drop needs at least protocol v{}, we speak v{PROTOCOL_VERSION}",
+            peer_hello.minimum_protocol_version
+        ));
+    }
+
+    Ok(PeerInfo {
+        protocol_version: peer_hello.protocol_version,
+        supported_types: peer_hello
+            .supported_types
+            .into_iter()
+            .filter_map(DropBoundType::from_i32)
+            .collect(),
+    })
+}
+
+/**
+Puts a frame back at the front of `buffered_reader`, to be read again
+*/
+fn unread(buffered_reader: &mut ReadStream, frame: &[u8]) -> Result<(), anyhow::Error> {
+    let mut codec = buffered_reader.decoder().clone();
+    let buffer = buffered_reader.read_buffer_mut();
+    let rest = buffer.split();
+    codec.encode(frame, buffer)?;
+    buffer.unsplit(rest);
+    Ok(())
+}
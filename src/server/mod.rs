@@ -1,65 +1,170 @@
-use std::{mem, sync::Arc};
+use std::{
+    mem,
+    sync::{Arc, PoisonError},
+    time::{Duration, Instant},
+};
 
 use anyhow::anyhow;
+use bytes::BytesMut;
+use futures_util::{SinkExt as _, StreamExt as _};
 use log::{info, warn};
 use protobuf::{EnumOrUnknown, Message};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt as _, BufReader},
-    net::{
-        TcpListener,
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-    },
+    io::AsyncWrite,
     spawn,
-    sync::Mutex,
+    sync::{Mutex, Semaphore, mpsc, watch},
+    time::{sleep, timeout, timeout_at},
 };
-use waitmap::WaitMap;
+use tokio_util::{codec::FramedWrite, sync::CancellationToken, task::TaskTracker};
 
 use crate::{
+    config::{Secret, SharedConfig},
+    downloads::{
+        cache::ContextCache,
+        invalidation::invalidate_rpc,
+        prewarm::{PrewarmRequest, prewarm_rpc},
+    },
     droplet::{
         backend::{has_backend_rpc, list_files_rpc, peek_file_rpc},
         call_rpc,
         cert::generate_client_cert_rpc,
         manifest::generate_manifest_rpc,
     },
-    proto::core::{DropBound, DropBoundType, TorrentialBound, TorrentialBoundType},
+    metrics::METRICS,
+    proto::{
+        core::{DropBound, DropBoundType, Shutdown, TorrentialBound, TorrentialBoundType},
+        droplet::RpcError,
+    },
+    server::{
+        auth::authenticate,
+        codec::{Frame, FrameCodec},
+        heartbeat::heartbeat_subroutine,
+        hello::{PeerInfo, is_reply, negotiate},
+        pending::{PendingGuard, PendingRequests, RequestError, is_idempotent},
+        transport::{LinkAddress, Listener, ReadStream, WriteStream},
+    },
 };
 
+pub mod auth;
+pub mod codec;
 pub mod download;
+pub mod heartbeat;
+pub mod hello;
+pub mod pending;
+pub mod transport;
 
 macro_rules! spawn_rpc {
     ($myself:ident, $message:ident, $func_name:ident) => {
-        spawn(async move { call_rpc($myself.clone(), $message, $func_name).await });
+        if $myself.shutdown.is_cancelled() {
+            $myself
+                .reject_message(&$message, "torrential is shutting down".to_owned())
+                .await;
+        } else {
+            let server = $myself.clone();
+            $myself
+                .rpc_tasks
+                .spawn(async move { call_rpc(server, $message, $func_name).await });
+        }
     };
 }
 
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+Every accepted connection from Drop gets a new generation, so requests
+can tell whether the socket they were sent on is still alive
+*/
+#[derive(Debug, Clone)]
+pub struct ConnectionState {
+    pub generation: u64,
+    pub connected: bool,
+    pub peer: PeerInfo,
+    /// Round trip of the last answered heartbeat
+    pub rtt: Option<Duration>,
+    pub missed_heartbeats: u32,
+}
+
+impl ConnectionState {
+    fn new(generation: u64, peer: PeerInfo) -> Self {
+        Self {
+            generation,
+            connected: true,
+            peer,
+            rtt: None,
+            missed_heartbeats: 0,
+        }
+    }
+
+    /**
+    Before Drop first connects. Generations of real connections start at 1
+    */
+    fn waiting() -> Self {
+        Self {
+            connected: false,
+            ..Self::new(0, PeerInfo::default())
+        }
+    }
+}
+
 pub struct DropServer {
-    server: TcpListener,
-    write_stream: Mutex<OwnedWriteHalf>,
-    waitmap: WaitMap<String, TorrentialBound>,
+    config: Arc<SharedConfig>,
+    server: Listener,
+    write_stream: Mutex<WriteStream>,
+    connection: watch::Sender<ConnectionState>,
+    pending: PendingRequests,
+    last_message: std::sync::Mutex<Instant>,
+    /// Limits how many files manifest generation reads at once
+    reader_semaphore: Semaphore,
+    /// RPCs from Drop we're working on
+    rpc_tasks: TaskTracker,
+    /// For Drop to invalidate
+    context_cache: Arc<ContextCache>,
+    /// Picked up by `prewarm_subroutine`, which can create contexts
+    prewarm_requests: mpsc::UnboundedSender<PrewarmRequest>,
+    shutdown: CancellationToken,
 }
 
 impl DropServer {
     /**
     Reads from the socket, and tries to parse it into a message,
-    and then updates the waitmap with the corresponding message ID
-    and content
+    and then hands it to the request waiting on the corresponding
+    message ID
     */
     async fn recieve_loop(
         myself: Arc<DropServer>,
-        buffered_reader: &mut BufReader<OwnedReadHalf>,
+        buffered_reader: &mut ReadStream,
     ) -> Result<(), anyhow::Error> {
-        let mut length_buffer: [u8; 8] = [0; 8];
-        buffered_reader.read_exact(&mut length_buffer).await?;
-
-        let length = usize::from_le_bytes(length_buffer);
-        let mut buffer = vec![0; length];
-
-        buffered_reader.read_exact(&mut buffer).await?;
+        let frame = next_frame(buffered_reader).await?;
+        *myself
+            .last_message
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+        let buffer = match frame {
+            Frame::Whole(buffer) => buffer,
+            Frame::Skipped { length, .. } => {
+                myself.skipped_frame(frame.message_id(), length).await;
+                return Ok(());
+            }
+        };
 
+        // Frames only carry a length, so if the contents are garbage we can't
+        // trust where the next one starts either. Dropping the connection is
+        // the only safe way to get back in sync
         let message = TorrentialBound::parse_from_bytes(&buffer)
-            .expect("response didn't deserialize correctly");
+            .map_err(|err| anyhow!("failed to decode message from drop: {err}"))?;
+
+        let message_type = match message.type_.enum_value() {
+            Ok(message_type) => message_type,
+            Err(value) => {
+                myself
+                    .reject_message(&message, format!("unknown message type {value}"))
+                    .await;
+                return Ok(());
+            }
+        };
 
-        match message.type_.unwrap() {
+        match message_type {
             TorrentialBoundType::GENERATE_MANIFEST => {
                 spawn_rpc!(myself, message, generate_manifest_rpc);
             }
@@ -78,8 +183,28 @@ impl DropServer {
             TorrentialBoundType::PEEK_FILE_QUERY => {
                 spawn_rpc!(myself, message, peek_file_rpc);
             }
+            TorrentialBoundType::INVALIDATE => {
+                spawn_rpc!(myself, message, invalidate_rpc);
+            }
+            TorrentialBoundType::PREWARM => {
+                spawn_rpc!(myself, message, prewarm_rpc);
+            }
+            TorrentialBoundType::ERROR
+            | TorrentialBoundType::SERVER_GAMES_RESPONSE
+            | TorrentialBoundType::VERSION_RESPONSE
+            | TorrentialBoundType::PONG => {
+                let message_id = message.message_id.clone();
+                if !myself.pending.complete(message) {
+                    warn!("dropping reply for abandoned message {message_id}");
+                }
+            }
             _ => {
-                myself.waitmap.insert(message.message_id.clone(), message);
+                myself
+                    .reject_message(
+                        &message,
+                        format!("unsupported message type {message_type:?}"),
+                    )
+                    .await;
             }
         }
 
@@ -87,54 +212,395 @@ impl DropServer {
     }
 
     /**
-    Long-lived subroutine that never returns, runs the `recieve_loop` and reconnects
-    as necessary
+    Long-lived subroutine that never returns. Waits for Drop to connect,
+    runs the `recieve_loop` until the connection fails, and starts again
     */
-    async fn recieve_subroutine(myself: Arc<DropServer>, read_stream: OwnedReadHalf) -> ! {
-        let mut buffered_reader = BufReader::new(read_stream);
-
+    async fn recieve_subroutine(myself: Arc<DropServer>) -> ! {
         loop {
-            if let Err(err) = Self::recieve_loop(myself.clone(), &mut buffered_reader).await {
-                warn!("server disconnected with error: {err:?}");
-
-                let (drop_stream, _) = myself
-                    .server
-                    .accept()
-                    .await
-                    .expect("failed to accept new listener");
-                let (read, mut write) = drop_stream.into_split();
+            let mut buffered_reader = myself.connect().await;
 
-                info!("reconnected to drop server");
+            let err = loop {
+                // The heartbeat marks the link as disconnected if Drop stops answering
+                let mut connection = myself.connection.subscribe();
+                let result = tokio::select! {
+                    result = Self::recieve_loop(myself.clone(), &mut buffered_reader) => result,
+                    _ = connection.wait_for(|state| !state.connected) => {
+                        Err(anyhow!("drop stopped answering heartbeats"))
+                    }
+                };
+                if let Err(err) = result {
+                    break err;
+                }
+            };
 
-                let mut lock = myself.write_stream.lock().await;
-                mem::swap(&mut *lock, &mut write);
+            warn!("server disconnected with error: {err:?}");
+            myself
+                .connection
+                .send_modify(|state| state.connected = false);
+            // Close our half too, so Drop notices and reconnects. A writer
+            // stuck on a full socket holds the lock, so don't wait forever
+            let close = timeout(CLOSE_TIMEOUT, async {
+                myself.write_stream.lock().await.close().await
+            });
+            match close.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("failed to close drop connection: {err}"),
+                Err(_) => warn!("timed out closing drop connection"),
+            }
+            // Nothing sent on this connection is going to be answered now
+            myself.fail_requests_before(myself.connection.borrow().generation + 1);
+        }
+    }
 
-                let mut new_reader = BufReader::new(read);
-                mem::swap(&mut buffered_reader, &mut new_reader);
+    /**
+    Accepts the next connection from Drop and makes it the current one
+    */
+    async fn connect(&self) -> ReadStream {
+        let (buffered_reader, mut write, peer) = loop {
+            match accept_connection(&self.server, &self.config).await {
+                Ok(connection) => break connection,
+                Err(err) => {
+                    warn!("failed to accept drop connection: {err:?}");
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                }
             }
+        };
+
+        {
+            let mut lock = self.write_stream.lock().await;
+            mem::swap(&mut *lock, &mut write);
+            self.connection.send_modify(|state| {
+                *state = ConnectionState::new(state.generation + 1, peer);
+            });
+        };
+        // Anything sent while we were waiting went to the dead socket
+        self.fail_requests_before(self.connection.borrow().generation);
+        info!("connected to drop server");
+
+        buffered_reader
+    }
+
+    /**
+    Fails every request that was sent on a connection older than `generation`
+    */
+    fn fail_requests_before(&self, generation: u64) {
+        let failed = self.pending.fail_before(generation);
+        if failed > 0 {
+            warn!("failed {failed} in-flight requests to drop");
         }
     }
 
+    pub(crate) fn reader_semaphore(&self) -> &Semaphore {
+        &self.reader_semaphore
+    }
+
+    #[must_use]
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
     /**
-    Uses the waitmap to wait for a response from a query
+    Resolves once shutdown has started. Long-running RPCs should give up
+    when this does
     */
-    pub async fn wait_for_message_id<T>(&self, message_id: &str) -> Result<T, anyhow::Error>
+    pub async fn shutting_down(&self) {
+        self.shutdown.cancelled().await;
+    }
+
+    /**
+    Runs `task` alongside the RPCs from Drop, so shutdown waits for it too.
+    It should give up once `shutting_down` resolves
+    */
+    pub fn spawn_tracked<F>(&self, task: F)
     where
-        T: protobuf::Message,
+        F: Future<Output = ()> + Send + 'static,
     {
-        let message = self
-            .waitmap
-            .wait(message_id)
+        self.rpc_tasks.spawn(task);
+    }
+
+    /**
+    Tells Drop we're going away, and cancels RPCs in progress, waiting
+    up to `drain` for them to report back to Drop
+    */
+    pub async fn shutdown(&self, drain: Duration) {
+        self.shutdown.cancel();
+
+        if self
+            .connection_state()
+            .peer
+            .supports(DropBoundType::SHUTDOWN)
+        {
+            let mut message = Shutdown::new();
+            message.drain_seconds = u32::try_from(drain.as_secs()).unwrap_or(u32::MAX);
+            if let Err(err) = self
+                .send_message(DropBoundType::SHUTDOWN, message, None)
+                .await
+            {
+                warn!("failed to tell drop we're shutting down: {err:?}");
+            }
+        }
+
+        self.rpc_tasks.close();
+        if !self.rpc_tasks.is_empty() {
+            info!("cancelling {} rpcs from drop", self.rpc_tasks.len());
+        }
+        if timeout(drain, self.rpc_tasks.wait()).await.is_err() {
+            warn!(
+                "gave up waiting for {} rpcs from drop to finish",
+                self.rpc_tasks.len()
+            );
+        }
+    }
+
+    /**
+    Manifest reader permits that are free right now, out of the total
+    */
+    #[must_use]
+    pub fn reader_permits(&self) -> (usize, usize) {
+        (
+            self.reader_semaphore.available_permits(),
+            self.config.get().reader_threads,
+        )
+    }
+
+    pub(crate) fn context_cache(&self) -> &ContextCache {
+        &self.context_cache
+    }
+
+    pub(crate) fn prewarm(&self, request: PrewarmRequest) -> Result<(), anyhow::Error> {
+        self.prewarm_requests
+            .send(request)
+            .map_err(|_| anyhow!("prewarming isn't running"))
+    }
+
+    #[must_use]
+    pub fn connection_state(&self) -> ConnectionState {
+        self.connection.borrow().clone()
+    }
+
+    /**
+    Resolves once Drop is connected, straight away if it already is
+    */
+    pub async fn connected(&self) {
+        // Can't fail, we hold the sender
+        let _ = self
+            .connection
+            .subscribe()
+            .wait_for(|state| state.connected)
+            .await;
+    }
+
+    /**
+    Time since we last read anything from Drop
+    */
+    #[must_use]
+    pub fn since_last_message(&self) -> Duration {
+        self.last_message
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .elapsed()
+    }
+
+    /**
+    Tells Drop we can't handle a message, rather than leaving it waiting
+    */
+    async fn reject_message(&self, message: &TorrentialBound, error: String) {
+        warn!("rejecting message {}: {error}", message.message_id);
+        let mut rpc_err = RpcError::new();
+        rpc_err.error = error;
+        let _ = self
+            .send_message(
+                DropBoundType::RPC_ERROR,
+                rpc_err,
+                Some(message.message_id.clone()),
+            )
             .await
-            .ok_or(anyhow!("no response returned for value"))?;
+            .inspect_err(|err| warn!("failed to send rpc err: {err:?}"));
+    }
 
-        let message = message.value();
+    /**
+    Fails the request an oversized frame answered, so it isn't re-sent
+    and answered the same way again. Anything else Drop sent that big is
+    refused
+    */
+    async fn skipped_frame(&self, message_id: Option<String>, length: u64) {
+        let Some(message_id) = message_id else {
+            warn!("skipped a message of {length} bytes from drop");
+            return;
+        };
+        let too_large = |message_type| RequestError::TooLarge {
+            message_type,
+            length,
+        };
+        if self.pending.fail(&message_id, too_large) {
+            return;
+        }
+        let mut message = TorrentialBound::new();
+        message.message_id = message_id;
+        self.reject_message(
+            &message,
+            format!("message of {length} bytes is over link.max_frame_size"),
+        )
+        .await;
+    }
 
-        if message.type_.unwrap() == crate::proto::core::TorrentialBoundType::ERROR {
-            Err(anyhow!(String::from_utf8(message.data.clone()).unwrap()))
+    /**
+    Errors if the connected Drop didn't list `message_type` in its hello.
+    Replies to Drop are always allowed
+    */
+    fn check_supported(&self, message_type: DropBoundType) -> Result<(), RequestError> {
+        let state = self.connection.borrow();
+        // Until Drop first connects we don't know, so let requests wait for it
+        if is_reply(message_type) || state.generation == 0 || state.peer.supports(message_type) {
+            Ok(())
         } else {
-            let response = T::parse_from_bytes(&message.data)?;
-            Ok(response)
+            Err(RequestError::Unsupported { message_type })
+        }
+    }
+
+    /**
+    Writes a frame to the current connection, failing straight away if
+    there isn't one rather than writing to a dead socket
+    */
+    async fn write_connected(
+        &self,
+        write_stream: &mut WriteStream,
+        buf: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        if !self.connection.borrow().connected {
+            return Err(anyhow!("drop isn't connected"));
+        }
+        write_frame(write_stream, buf).await
+    }
+
+    /**
+    Sends a query to Drop and waits for the reply, giving up after the
+    deadline configured for `message_type`. Idempotent queries are sent
+    again if the connection drops before Drop answers
+    */
+    pub async fn request<Q, R>(
+        &self,
+        message_type: DropBoundType,
+        message: Q,
+    ) -> Result<R, anyhow::Error>
+    where
+        Q: protobuf::Message,
+        R: protobuf::Message,
+    {
+        let type_label = format!("{message_type:?}");
+        let started = Instant::now();
+        let result = self
+            .exchange(message_type, &message)
+            .await
+            .and_then(|reply| {
+                METRICS
+                    .drop_rpc
+                    .with_label_values(&[&type_label])
+                    .observe(started.elapsed().as_secs_f64());
+                if reply.type_.enum_value() == Ok(TorrentialBoundType::ERROR) {
+                    Err(anyhow!(String::from_utf8_lossy(&reply.data).into_owned()))
+                } else {
+                    Ok(R::parse_from_bytes(&reply.data)?)
+                }
+            });
+
+        if let Err(err) = &result {
+            warn!("{message_type:?} failed: {err:#}");
+            METRICS
+                .drop_rpc_failures
+                .with_label_values(&[&type_label])
+                .inc();
+        }
+        result
+    }
+
+    /**
+    Sends a query and waits for whatever Drop answers with, re-sending it
+    after a disconnect if it's idempotent
+    */
+    async fn exchange<Q>(
+        &self,
+        message_type: DropBoundType,
+        message: &Q,
+    ) -> Result<TorrentialBound, anyhow::Error>
+    where
+        Q: protobuf::Message,
+    {
+        self.check_supported(message_type)?;
+        let timeout = self.config.get().rpc.timeout_for(message_type);
+        let deadline = Instant::now() + timeout;
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let buf = encode_message(message_type, message, message_id.clone())?;
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            message_id: &message_id,
+        };
+
+        loop {
+            let (generation, sent) = {
+                let mut mutex_lock = self.write_stream.lock().await;
+                let generation = self.connection.borrow().generation;
+                let reply = self
+                    .pending
+                    .register(message_id.clone(), message_type, generation);
+                let sent = self.write_connected(&mut mutex_lock, &buf).await;
+                if sent.is_ok() {
+                    METRICS
+                        .drop_messages_sent
+                        .with_label_values(&[&format!("{message_type:?}")])
+                        .inc();
+                }
+                (generation, sent.map(|()| reply))
+            };
+
+            let reply = match sent {
+                Ok(reply) => match timeout_at(deadline.into(), reply).await {
+                    Ok(Ok(reply)) => reply,
+                    // Dropped without an answer, which only a disconnect does
+                    Ok(Err(_)) => Err(RequestError::Disconnected { message_type }),
+                    Err(_) => Err(RequestError::Timeout {
+                        message_type,
+                        timeout,
+                    }),
+                },
+                Err(err) => {
+                    warn!("failed to send {message_type:?}: {err:?}");
+                    Err(RequestError::Disconnected { message_type })
+                }
+            };
+
+            match reply {
+                Ok(message) => return Ok(message),
+                Err(RequestError::Disconnected { .. }) if is_idempotent(message_type) => {
+                    info!("re-sending {message_type:?} ({message_id}) after disconnect");
+                    self.wait_for_reconnect(generation, deadline, timeout, message_type)
+                        .await?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    async fn wait_for_reconnect(
+        &self,
+        generation: u64,
+        deadline: Instant,
+        timeout: std::time::Duration,
+        message_type: DropBoundType,
+    ) -> Result<(), RequestError> {
+        let mut state = self.connection.subscribe();
+        match timeout_at(
+            deadline.into(),
+            state.wait_for(|state| state.connected && state.generation > generation),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => Err(RequestError::Disconnected { message_type }),
+            Err(_) => Err(RequestError::Timeout {
+                message_type,
+                timeout,
+            }),
         }
     }
 
@@ -150,43 +616,151 @@ impl DropServer {
     where
         T: protobuf::Message,
     {
-        let mut query = DropBound::new();
-        query.message_id = message_id.unwrap_or(uuid::Uuid::new_v4().to_string());
-        query.type_ = EnumOrUnknown::new(message_type);
-        query.data = Vec::new();
-        message.write_to_vec(&mut query.data)?;
-
-        let mut buf = Vec::new();
-        query.write_to_vec(&mut buf)?;
+        self.check_supported(message_type)?;
+        let message_id = message_id.unwrap_or(uuid::Uuid::new_v4().to_string());
+        let buf = encode_message(message_type, &message, message_id.clone())?;
 
         {
             let mut mutex_lock = self.write_stream.lock().await;
-            mutex_lock.write(&buf.len().to_le_bytes()).await?;
-            mutex_lock.write_all(&buf).await?;
+            self.write_connected(&mut mutex_lock, &buf).await?;
         };
+        METRICS
+            .drop_messages_sent
+            .with_label_values(&[&format!("{message_type:?}")])
+            .inc();
+
+        Ok(message_id)
+    }
+}
 
-        Ok(query.message_id)
+fn encode_message<T>(
+    message_type: DropBoundType,
+    message: &T,
+    message_id: String,
+) -> Result<Vec<u8>, anyhow::Error>
+where
+    T: protobuf::Message,
+{
+    let mut query = DropBound::new();
+    query.message_id = message_id;
+    query.type_ = EnumOrUnknown::new(message_type);
+    query.data = Vec::new();
+    message.write_to_vec(&mut query.data)?;
+
+    let mut buf = Vec::new();
+    query.write_to_vec(&mut buf)?;
+
+    Ok(buf)
+}
+
+async fn next_frame(buffered_reader: &mut ReadStream) -> Result<Frame, anyhow::Error> {
+    match buffered_reader.next().await {
+        Some(frame) => Ok(frame?),
+        None => Err(anyhow!("drop closed the connection")),
+    }
+}
+
+async fn read_frame(buffered_reader: &mut ReadStream) -> Result<BytesMut, anyhow::Error> {
+    match next_frame(buffered_reader).await? {
+        Frame::Whole(frame) => Ok(frame),
+        Frame::Skipped { length, .. } => Err(anyhow!(
+            "frame of {length} bytes is over link.max_frame_size"
+        )),
     }
 }
 
+async fn write_frame(write_stream: &mut WriteStream, buf: &[u8]) -> Result<(), anyhow::Error> {
+    write_stream.send(buf).await?;
+    Ok(())
+}
+
 /**
-Spins up the TCP listener, and waits for the first client to connect
-Also starts the recieve subroutine
+Accepts connections until one authenticates and agrees on a protocol
+version with us. Anything else is logged and dropped
 */
-pub async fn create_drop_server() -> Result<Arc<DropServer>, anyhow::Error> {
-    let server = TcpListener::bind("127.0.0.1:33148").await?;
+async fn accept_connection(
+    server: &Listener,
+    config: &SharedConfig,
+) -> Result<(ReadStream, WriteStream, PeerInfo), anyhow::Error> {
+    loop {
+        let config = config.get();
+        let config = &config.link;
+        let secret = config.secret.as_ref().map(Secret::as_bytes);
+        let (mut buffered_reader, mut write, peer) = server
+            .accept(FrameCodec::new(config.max_frame_size))
+            .await?;
+
+        // Waiting for a hello is how a v0 Drop is told apart, so that has to
+        // end with the handshake rather than time it out
+        let deadline = tokio::time::Instant::now() + config.handshake_timeout();
+        let result = match timeout_at(
+            deadline,
+            authenticate(secret, &mut buffered_reader, &mut write),
+        )
+        .await
+        {
+            Ok(Ok(())) => negotiate(&mut buffered_reader, &mut write, deadline).await,
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(anyhow!("handshake timed out")),
+        };
+
+        match result {
+            Ok(info) => {
+                info!(
+                    "accepted drop connection from {peer} (protocol v{})",
+                    info.protocol_version
+                );
+                buffered_reader.decoder_mut().skip_oversized();
+                return Ok((buffered_reader, write, info));
+            }
+            Err(err) => warn!("rejected drop connection from {peer}: {err}"),
+        }
+    }
+}
 
-    let (drop_stream, _) = server.accept().await?;
+/**
+Spins up the listener configured in `config.link`, and starts the recieve
+subroutine, which waits for Drop to connect and authenticate. Until it
+does, requests to Drop wait for it or time out
+*/
+pub async fn create_drop_server(
+    config: Arc<SharedConfig>,
+    context_cache: Arc<ContextCache>,
+    prewarm_requests: mpsc::UnboundedSender<PrewarmRequest>,
+) -> Result<Arc<DropServer>, anyhow::Error> {
+    let initial = config.get();
+    if initial.link.allow_unauthenticated && initial.link.secret.is_none() {
+        warn!("no link secret configured, accepting unauthenticated connections");
+    }
+    let server = Listener::bind(
+        &LinkAddress::parse(&initial.link.address)?,
+        initial.link.socket_mode,
+    )
+    .await?;
 
-    let (read, write) = drop_stream.into_split();
+    // Never written to, as we aren't connected yet
+    let placeholder = FramedWrite::new(
+        Box::new(tokio::io::sink()) as Box<dyn AsyncWrite + Send + Unpin>,
+        FrameCodec::new(initial.link.max_frame_size),
+    );
 
     let client = Arc::new(DropServer {
+        reader_semaphore: Semaphore::new(initial.reader_threads),
+        config,
         server,
-        write_stream: Mutex::new(write),
-        waitmap: WaitMap::new(),
+        write_stream: Mutex::new(placeholder),
+        connection: watch::Sender::new(ConnectionState::waiting()),
+        pending: PendingRequests::default(),
+        last_message: std::sync::Mutex::new(Instant::now()),
+        rpc_tasks: TaskTracker::new(),
+        context_cache,
+        prewarm_requests,
+        shutdown: CancellationToken::new(),
     });
 
-    spawn(DropServer::recieve_subroutine(client.clone(), read));
+    info!("waiting for drop to connect on {}", initial.link.address);
+    spawn(DropServer::recieve_subroutine(client.clone()));
+    spawn(heartbeat_subroutine(client.clone()));
 
     info!("created client subroutine");
 
@@ -0,0 +1,210 @@
+use std::{fmt::Display, time::Duration};
+
+use dashmap::DashMap;
+use reqwest::StatusCode;
+use tokio::sync::oneshot;
+
+use crate::proto::core::{DropBoundType, TorrentialBound};
+
+pub type Reply = Result<TorrentialBound, RequestError>;
+
+/**
+Queries that are safe to send again on a new connection if the one they
+were sent on goes away before Drop answers
+*/
+#[must_use]
+pub fn is_idempotent(message_type: DropBoundType) -> bool {
+    matches!(
+        message_type,
+        DropBoundType::VERSION_QUERY | DropBoundType::SERVER_GAMES_QUERY
+    )
+}
+
+/**
+Failures of a query to Drop that aren't errors returned by Drop itself
+*/
+#[derive(Debug)]
+pub enum RequestError {
+    Timeout {
+        message_type: DropBoundType,
+        timeout: Duration,
+    },
+    Disconnected {
+        message_type: DropBoundType,
+    },
+    Unsupported {
+        message_type: DropBoundType,
+    },
+    /// Drop's answer was over `link.max_frame_size`, so it was skipped
+    TooLarge {
+        message_type: DropBoundType,
+        length: u64,
+    },
+}
+
+impl RequestError {
+    #[must_use]
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RequestError::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            RequestError::Disconnected { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            RequestError::Unsupported { .. } | RequestError::TooLarge { .. } => {
+                StatusCode::BAD_GATEWAY
+            }
+        }
+    }
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout {
+                message_type,
+                timeout,
+            } => write!(
+                f,
+                "drop didn't answer {message_type:?} within {}s",
+                timeout.as_secs()
+            ),
+            RequestError::Disconnected { message_type } => {
+                write!(f, "drop disconnected before answering {message_type:?}")
+            }
+            RequestError::Unsupported { message_type } => {
+                write!(f, "drop doesn't support {message_type:?}")
+            }
+            RequestError::TooLarge {
+                message_type,
+                length,
+            } => write!(
+                f,
+                "drop's answer to {message_type:?} was {length} bytes, over link.max_frame_size"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+struct PendingRequest {
+    sender: oneshot::Sender<Reply>,
+    message_type: DropBoundType,
+    generation: u64,
+}
+
+/**
+Queries that have been sent to Drop and are waiting on a reply,
+keyed by message ID
+*/
+#[derive(Default)]
+pub struct PendingRequests {
+    requests: DashMap<String, PendingRequest>,
+}
+
+impl PendingRequests {
+    /**
+    Registers interest in a reply. Must be called before the query is sent,
+    otherwise a fast reply could arrive before anyone is waiting for it
+    */
+    #[must_use]
+    pub fn register(
+        &self,
+        message_id: String,
+        message_type: DropBoundType,
+        generation: u64,
+    ) -> oneshot::Receiver<Reply> {
+        let (sender, recieve) = oneshot::channel();
+        self.requests.insert(
+            message_id,
+            PendingRequest {
+                sender,
+                message_type,
+                generation,
+            },
+        );
+        recieve
+    }
+
+    /**
+    Hands a reply to its waiter. Returns false if nobody is waiting on it
+    anymore
+    */
+    pub fn complete(&self, message: TorrentialBound) -> bool {
+        match self.requests.remove(&message.message_id) {
+            Some((_, pending)) => pending.sender.send(Ok(message)).is_ok(),
+            None => false,
+        }
+    }
+
+    /**
+    Fails a single request with whatever `error` makes of its type. Returns
+    false if nobody is waiting on it
+    */
+    pub fn fail(
+        &self,
+        message_id: &str,
+        error: impl FnOnce(DropBoundType) -> RequestError,
+    ) -> bool {
+        match self.requests.remove(message_id) {
+            Some((_, pending)) => pending
+                .sender
+                .send(Err(error(pending.message_type)))
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /**
+    Fails every request that was sent on a connection older than
+    `generation`, returning how many were failed
+    */
+    #[must_use]
+    pub fn fail_before(&self, generation: u64) -> usize {
+        let stale = self
+            .requests
+            .iter()
+            .filter(|pending| pending.generation < generation)
+            .map(|pending| pending.key().clone())
+            .collect::<Vec<String>>();
+
+        let mut failed = 0;
+        for message_id in stale {
+            if let Some((_, pending)) = self.requests.remove(&message_id) {
+                let message_type = pending.message_type;
+                let _ = pending
+                    .sender
+                    .send(Err(RequestError::Disconnected { message_type }));
+                failed += 1;
+            }
+        }
+        failed
+    }
+
+    pub fn abandon(&self, message_id: &str) {
+        self.requests.remove(message_id);
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+/**
+Removes the pending entry when the waiting future finishes or is dropped
+(for example, when the HTTP client goes away)
+*/
+pub(crate) struct PendingGuard<'a> {
+    pub(crate) pending: &'a PendingRequests,
+    pub(crate) message_id: &'a str,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.abandon(self.message_id);
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt::Display;
+
+use anyhow::anyhow;
+use log::info;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::server::codec::FrameCodec;
+
+pub type ReadStream = FramedRead<Box<dyn AsyncRead + Send + Unpin>, FrameCodec>;
+pub type WriteStream = FramedWrite<Box<dyn AsyncWrite + Send + Unpin>, FrameCodec>;
+
+/**
+Where to listen for Drop. Either `tcp://host:port`,
+or `unix:///path/to/socket` for deployments where Drop runs alongside us
+*/
+#[derive(Debug, Clone)]
+pub enum LinkAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl LinkAddress {
+    pub fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        if let Some(address) = value.strip_prefix("tcp://") {
+            return Ok(Self::Tcp(address.to_owned()));
+        }
+        #[cfg(unix)]
+        if let Some(path) = value.strip_prefix("unix://") {
+            return Ok(Self::Unix(path.into()));
+        }
+        Err(anyhow!("unsupported drop link address: {value}"))
+    }
+}
+
+impl Display for LinkAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkAddress::Tcp(address) => write!(f, "tcp://{address}"),
+            #[cfg(unix)]
+            LinkAddress::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /**
+    Starts listening on `address`. A Unix socket gets `socket_mode` as its
+    file mode
+    */
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub async fn bind(address: &LinkAddress, socket_mode: u32) -> Result<Self, anyhow::Error> {
+        let listener = match address {
+            LinkAddress::Tcp(address) => Self::Tcp(TcpListener::bind(address).await?),
+            #[cfg(unix)]
+            LinkAddress::Unix(path) => Self::Unix(bind_unix(path, socket_mode)?),
+        };
+        info!("listening for drop on {address}");
+        Ok(listener)
+    }
+
+    /**
+    Accepts the next connection, returning its halves and a description
+    of the peer for logging
+    */
+    pub async fn accept(
+        &self,
+        codec: FrameCodec,
+    ) -> Result<(ReadStream, WriteStream, String), std::io::Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                let (read, write) = stream.into_split();
+                Ok((
+                    FramedRead::new(Box::new(read), codec.clone()),
+                    FramedWrite::new(Box::new(write), codec),
+                    address.to_string(),
+                ))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                let peer = stream.peer_cred().map_or("unix socket".to_owned(), |cred| {
+                    format!("unix socket (pid {:?}, uid {})", cred.pid(), cred.uid())
+                });
+                let (read, write) = stream.into_split();
+                Ok((
+                    FramedRead::new(Box::new(read), codec.clone()),
+                    FramedWrite::new(Box::new(write), codec),
+                    peer,
+                ))
+            }
+        }
+    }
+}
+
+/**
+Binds a Unix socket at `path` without it ever being reachable with looser
+permissions than `socket_mode`. It's bound inside a new directory only we
+can open, given its mode there, and then moved into place
+*/
+#[cfg(unix)]
+fn bind_unix(
+    path: &std::path::Path,
+    socket_mode: u32,
+) -> Result<tokio::net::UnixListener, anyhow::Error> {
+    use std::{
+        fs,
+        io::ErrorKind,
+        os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _},
+    };
+
+    use anyhow::Context as _;
+    use log::warn;
+
+    // A socket left behind by a previous run would stop us binding, but
+    // anything else there isn't ours to delete
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(anyhow!(
+                "{} already exists and isn't a socket",
+                path.display()
+            ));
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} isn't a file path", path.display()))?;
+    // Not named by our PID, which is the same on every restart in a
+    // container, so a directory left by a crash can't get in the way
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        uuid::Uuid::new_v4().simple()
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let staged = staging.join("socket");
+    let bound = tokio::net::UnixListener::bind(&staged)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(socket_mode))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        })
+        .with_context(|| format!("failed to bind {}", path.display()));
+    if let Err(err) = fs::remove_dir_all(&staging) {
+        warn!("failed to remove {}: {err}", staging.display());
+    }
+    bound
+}
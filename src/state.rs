@@ -1,10 +1,13 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
-use dashmap::DashMap;
+use dashmap::DashSet;
 
-use crate::{DownloadContext, server::DropServer};
+use crate::{config::SharedConfig, downloads::cache::ContextCache, server::DropServer};
 
 pub struct AppState {
-    pub context_cache: DashMap<(String, String), DownloadContext>,
+    pub config: Arc<SharedConfig>,
+    pub context_cache: Arc<ContextCache>,
     pub server: Arc<DropServer>,
+    /// Library base directories we've served from, checked by the healthcheck
+    pub library_dirs: DashSet<PathBuf>,
 }
@@ -1,8 +1,19 @@
-use log::error;
+use log::{error, warn};
 use reqwest::StatusCode;
 
+use crate::server::pending::RequestError;
+
 #[derive(Debug)]
 pub struct ErrorOption(Result<StatusCode, anyhow::Error>);
+impl ErrorOption {
+    /**
+    The link failure behind this error, if it wasn't an answer from Drop
+    */
+    #[must_use]
+    pub fn request_error(&self) -> Option<&RequestError> {
+        self.0.as_ref().err()?.downcast_ref()
+    }
+}
 impl From<anyhow::Error> for ErrorOption {
     fn from(value: anyhow::Error) -> Self {
         Self(Err(value))
@@ -29,6 +40,10 @@ impl From<ErrorOption> for StatusCode {
         match value.0 {
             Ok(status) => status,
             Err(err) => {
+                if let Some(request_err) = err.downcast_ref::<RequestError>() {
+                    warn!("{request_err}");
+                    return request_err.status_code();
+                }
                 error!("{err:?}");
                 Self::INTERNAL_SERVER_ERROR
             }